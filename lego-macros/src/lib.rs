@@ -1,27 +1,24 @@
 use proc_macro::TokenStream;
 use quote::{format_ident, quote, ToTokens};
-use syn::visit_mut::{visit_block_mut, visit_expr_mut, VisitMut};
+use syn::visit_mut::{visit_expr_mut, VisitMut};
 use syn::{
     parse_macro_input, Attribute, Block, DataStruct, DeriveInput, Expr, Ident, Type, Visibility,
 };
 
 struct RewriteVisitor {
-    if_depth: usize,
+    /// number of staged `if`, `while` and `loop` we're nested in. Their bodies are rewritten into
+    /// closures, so `return`, `break` and `continue` must be rewritten too.
+    depth: usize,
+    /// the staged `while` and `loop` we're nested in, innermost last, and whether a `break`
+    /// passes them a value.
+    loops: Vec<bool>,
 }
 
 impl RewriteVisitor {
     fn new() -> Self {
-        Self { if_depth: 0 }
-    }
-}
-
-fn handle_control_flow(input: impl ToTokens) -> impl ToTokens {
-    quote! {
-        match #input {
-            lego::prelude::ControlFlow::Continue => unreachable!(),
-            lego::prelude::ControlFlow::Break(v) => return lego::prelude::ControlFlow::Break(v),
-            lego::prelude::ControlFlow::Ret(v) => return lego::prelude::ControlFlow::Ret(v),
-            lego::prelude::ControlFlow::Preempt => return lego::prelude::ControlFlow::Preempt,
+        Self {
+            depth: 0,
+            loops: Vec::new(),
         }
     }
 }
@@ -31,40 +28,32 @@ impl VisitMut for RewriteVisitor {
         unreachable!("return should be handled")
     }
 
-    // fn visit_expr_binary_mut(&mut self, _i: &mut syn::ExprBinary) {
-    //     unreachable!("binop should be rewritten");
-    // }
-
     fn visit_expr_mut(&mut self, e: &mut syn::Expr) {
         match e {
-            // conflict in implementation for integers.
-            // When doing 1 + 1 for example, what should be the output?
-            // This should emit a bool: we can evaluate this expression at compile time.
-            // This can be solved with a new trait
-            // Expr::Binary(i) => {
-            //
-            //     visit_expr_mut(self, &mut i.left);
-            //     visit_expr_mut(self, &mut i.right);
-            //
-            //     let new_e = match i.op {
-            //         syn::BinOp::Eq(_) => {
-            //             let lhs = &i.left;
-            //             let rhs = &i.right;
-            //             quote! {
-            //                 #lhs.eq(&#rhs)
-            //             }
-            //         },
-            //         syn::BinOp::Lt(_) => todo!(),
-            //         syn::BinOp::Le(_) => todo!(),
-            //         syn::BinOp::Ne(_) => todo!(),
-            //         syn::BinOp::Ge(_) => todo!(),
-            //         syn::BinOp::Gt(_) => todo!(),
-            //         _ => return,
-            //     };
-            //
-            //     *e = syn::parse(new_e.into()).unwrap();
-            //
-            // }
+            // comparisons are lowered to the staged comparison traits: comparing host values
+            // folds to a bool at build time, while comparing staged values emits a `Val<bool>`.
+            Expr::Binary(i) => {
+                self.visit_expr_mut(&mut i.left);
+                self.visit_expr_mut(&mut i.right);
+
+                let f = match i.op {
+                    syn::BinOp::Eq(_) => quote! { StagedPartialEq::staged_eq },
+                    syn::BinOp::Ne(_) => quote! { StagedPartialEq::staged_ne },
+                    syn::BinOp::Lt(_) => quote! { StagedPartialOrd::staged_lt },
+                    syn::BinOp::Le(_) => quote! { StagedPartialOrd::staged_le },
+                    syn::BinOp::Gt(_) => quote! { StagedPartialOrd::staged_gt },
+                    syn::BinOp::Ge(_) => quote! { StagedPartialOrd::staged_ge },
+                    _ => return,
+                };
+
+                let lhs = &i.left;
+                let rhs = &i.right;
+                let new_e = quote! {
+                    lego::prelude::#f(&(#lhs), &(#rhs))
+                };
+
+                *e = syn::parse(new_e.into()).unwrap();
+            }
             Expr::Call(call) => {
                 visit_expr_mut(self, &mut call.func);
                 call.args
@@ -80,7 +69,7 @@ impl VisitMut for RewriteVisitor {
                 *e = syn::parse(new_call.into()).unwrap();
             }
             Expr::If(i) => {
-                self.if_depth += 1;
+                self.depth += 1;
                 self.visit_expr_mut(&mut i.cond);
                 self.visit_block_mut(&mut i.then_branch);
                 if let Some((_, ref mut else_branch)) = i.else_branch {
@@ -95,75 +84,182 @@ impl VisitMut for RewriteVisitor {
                     quote! { () }
                 };
 
-                self.if_depth -= 1;
+                self.depth -= 1;
 
+                // branches are passed one after the other, so that they can borrow the same
+                // state
                 let new = quote! {
                     {
                         #[allow(unreachable_code)]
-                        lego::prelude::If::new(
-                            || #cond,
-                            |__ctx__| lego::prelude::ControlFlow::Break(#then),
-                            |__ctx__| lego::prelude::ControlFlow::Break(#alt),
-                        ).eval()
-                        // match r {
-                        //     lego::prelude::ControlFlow::Continue => return lego::prelude::ControlFlow::Continue,
-                        //     lego::prelude::ControlFlow::Break(v) => v,
-                        //     lego::prelude::ControlFlow::Ret(v) => return lego::prelude::ControlFlow::Ret(v),
-                        //     lego::prelude::ControlFlow::Preempt => return lego::prelude::ControlFlow::Preempt,
-                        // }
+                        lego::prelude::If::new(#cond)
+                            .then(|__ctx__| lego::prelude::ControlFlow::Break(#then))
+                            .otherwise(|__ctx__| lego::prelude::ControlFlow::Break(#alt))
                     }
                 }
                 .into();
 
                 *e = syn::parse::<Expr>(new).unwrap();
             }
-            Expr::Break(_) => {
-                panic!("break not supported")
+            Expr::Break(brk) => {
+                if brk.label.is_some() {
+                    panic!("labeled break not supported")
+                }
+
+                if self.depth == 0 {
+                    panic!("break must be nested in a staged if, while or loop")
+                }
+
+                if let Some(ref mut e) = brk.expr {
+                    self.visit_expr_mut(e);
+                }
+
+                let val = match brk.expr {
+                    Some(ref e) => quote! { #e },
+                    None => quote! { () },
+                };
+                // breaking out of a loop of this block is checked against the type of the loop,
+                // otherwise we're breaking out of the loop of a jiterator
+                let loop_break = match self.loops.last_mut() {
+                    Some(has_value) => {
+                        *has_value |= brk.expr.is_some();
+                        quote! { __loop__ }
+                    }
+                    None => quote! { lego::prelude::LoopBreak::enclosing() },
+                };
+                let new_brk = quote! {
+                    return lego::prelude::FlowControl::break_loop(__ctx__, #loop_break, #val)
+                };
+                *e = syn::parse(new_brk.into()).unwrap();
             }
-            Expr::Continue(_) => {
-                panic!("continue not supported")
+            Expr::Continue(cont) => {
+                if cont.label.is_some() {
+                    panic!("labeled continue not supported")
+                }
+
+                if self.depth == 0 {
+                    panic!("continue must be nested in a staged if, while or loop")
+                }
+
+                let new_cont = quote! {
+                    return lego::prelude::FlowControl::continue_loop(__ctx__)
+                };
+                *e = syn::parse(new_cont.into()).unwrap();
             }
             Expr::Return(ret) => {
                 if let Some(ref mut e) = ret.expr {
                     self.visit_expr_mut(e);
                 }
 
-                if self.if_depth != 0 {
+                if self.depth != 0 {
                     let ret_e = match ret.expr {
                         Some(ref e) => quote! { #e },
                         None => quote! { () },
                     };
-                    let handle_cflow = handle_control_flow(quote! { __ctx__.ret(#ret_e) });
                     let new_ret = quote! {
-                        #handle_cflow
+                        return lego::prelude::FlowControl::ret(__ctx__, #ret_e)
                     };
                     *e = syn::parse::<Expr>(new_ret.into()).unwrap();
                 }
             }
             Expr::While(while_expr) => {
-                visit_expr_mut(self, &mut while_expr.cond);
-                visit_block_mut(self, &mut while_expr.body);
+                self.depth += 1;
+                self.loops.push(false);
+                self.visit_expr_mut(&mut while_expr.cond);
+                self.visit_block_mut(&mut while_expr.body);
+                self.loops.pop();
+                self.depth -= 1;
+
                 let cond = &while_expr.cond;
                 let body = &while_expr.body;
                 let new_while = quote! {
                     {
+                        #[allow(unreachable_code)]
                         lego::prelude::do_while(|__ctx__| {
+                            let __loop__ = __ctx__.loop_break();
                             while __ctx__.cond(|| #cond) {
                                 #body
                             }
 
                             lego::prelude::ControlFlow::Break(())
                         })
-                        // match ret {
-                        //     lego::prelude::ControlFlow::Break(()) => (),
-                        //     lego::prelude::ControlFlow::Ret(v) => return lego::prelude::ControlFlow::Ret(v),
-                        //     lego::prelude::ControlFlow::Continue => todo!(),
-                        //     lego::prelude::ControlFlow::Preempt => return lego::prelude::ControlFlow::Preempt,
-                        // }
                     }
                 };
                 *e = syn::parse(new_while.into()).unwrap();
             }
+            Expr::Loop(loop_expr) => {
+                self.depth += 1;
+                self.loops.push(false);
+                self.visit_block_mut(&mut loop_expr.body);
+                let has_value = self.loops.pop().unwrap();
+                self.depth -= 1;
+
+                // without a value to break with, the loop can only evaluate to `()`
+                let do_loop = if has_value {
+                    quote! { lego::prelude::do_loop }
+                } else {
+                    quote! { lego::prelude::do_loop::<(), _> }
+                };
+                let body = &loop_expr.body;
+                let new_loop = quote! {
+                    {
+                        #[allow(unreachable_code)]
+                        #do_loop(|__ctx__| {
+                            let __loop__ = __ctx__.loop_break();
+                            while __ctx__.cond(|| true) {
+                                #body
+                            }
+
+                            lego::prelude::ControlFlow::Break(())
+                        })
+                    }
+                };
+                *e = syn::parse(new_loop.into()).unwrap();
+            }
+            Expr::Match(m) if m.attrs.iter().any(is_staged_attr) => {
+                m.attrs.retain(|attr| !is_staged_attr(attr));
+                if !is_staged_match(m) {
+                    panic!(
+                        "a staged match must have integer literal or constant arms without guards, \
+                         and end with a catch-all arm"
+                    )
+                }
+
+                self.visit_expr_mut(&mut m.expr);
+                self.depth += 1;
+                for arm in m.arms.iter_mut() {
+                    self.visit_expr_mut(&mut arm.body);
+                }
+                self.depth -= 1;
+
+                let scrutinee = &m.expr;
+                let (default_arm, arms) = m.arms.split_last().unwrap();
+                let cases = arms.iter().map(|arm| {
+                    let vals = match_case_values(&arm.pat).unwrap();
+                    let body = &arm.body;
+                    quote! {
+                        .cases([#(#vals),*], |__ctx__| lego::prelude::ControlFlow::Break(#body))
+                    }
+                });
+                // the scrutinee is `Copy`, a binding in the default arm is just a copy of it
+                let binding = match default_arm.pat {
+                    syn::Pat::Ident(ref i) => quote! { let #i = __scrutinee__; },
+                    _ => quote! {},
+                };
+                let default_body = &default_arm.body;
+                let new_match = quote! {
+                    {
+                        let __scrutinee__ = #scrutinee;
+                        #[allow(unreachable_code)]
+                        lego::prelude::Match::new(__scrutinee__)
+                            #(#cases)*
+                            .default(|__ctx__| {
+                                #binding
+                                lego::prelude::ControlFlow::Break(#default_body)
+                            })
+                    }
+                };
+                *e = syn::parse(new_match.into()).unwrap();
+            }
             e => visit_expr_mut(self, e),
         }
     }
@@ -177,6 +273,57 @@ impl VisitMut for RewriteVisitor {
     }
 }
 
+/// The `#[staged]` attribute, marking a `match` on a staged integer. Other matches are left
+/// alone, as the type of the scrutinee isn't known to the macro.
+fn is_staged_attr(attr: &Attribute) -> bool {
+    attr.path().is_ident("staged")
+}
+
+/// A `match` can be staged if all its arms but the last match integer constants, and the last
+/// one is a catch-all.
+fn is_staged_match(m: &syn::ExprMatch) -> bool {
+    let Some((default_arm, arms)) = m.arms.split_last() else {
+        return false;
+    };
+
+    let is_catch_all = match default_arm.pat {
+        syn::Pat::Wild(_) => true,
+        syn::Pat::Ident(ref i) => i.by_ref.is_none() && i.subpat.is_none(),
+        _ => false,
+    };
+
+    !arms.is_empty()
+        && is_catch_all
+        && m.arms.iter().all(|arm| arm.guard.is_none())
+        && arms.iter().all(|arm| match_case_values(&arm.pat).is_some())
+}
+
+/// The values matched by an arm pattern, if they are all integer literals or constants.
+///
+/// Only the last arm can bind the scrutinee, identifiers in the other arms are constants.
+fn match_case_values(pat: &syn::Pat) -> Option<Vec<&dyn ToTokens>> {
+    match pat {
+        syn::Pat::Lit(lit) => match lit.lit {
+            syn::Lit::Int(_) | syn::Lit::Byte(_) => Some(vec![lit]),
+            _ => None,
+        },
+        syn::Pat::Ident(i)
+            if i.by_ref.is_none() && i.mutability.is_none() && i.subpat.is_none() =>
+        {
+            Some(vec![&i.ident])
+        }
+        syn::Pat::Path(p) => Some(vec![p]),
+        syn::Pat::Or(or) => {
+            let mut vals = Vec::new();
+            for case in or.cases.iter() {
+                vals.extend(match_case_values(case)?);
+            }
+            Some(vals)
+        }
+        _ => None,
+    }
+}
+
 #[proc_macro]
 pub fn lego(input: TokenStream) -> TokenStream {
     let mut input = parse_macro_input!(input as Block);
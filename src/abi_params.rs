@@ -19,6 +19,12 @@ impl<T> ToAbiParams for &[T] {
     }
 }
 
+impl<T> ToAbiParams for &mut [T] {
+    fn to_abi_params(params: &mut Vec<AbiParam>) {
+        <&[T]>::to_abi_params(params);
+    }
+}
+
 impl<T> ToAbiParams for *mut T {
     fn to_abi_params(params: &mut Vec<AbiParam>) {
         params.push(AbiParam::new(<*mut T>::ty()));
@@ -54,6 +60,9 @@ impl_to_abi_params_primitive! {
     u64,
     usize,
     isize,
+    bool,
+    f32,
+    f64,
 }
 
 macro_rules! impl_to_abi_params_tuples {
@@ -6,7 +6,7 @@ use crate::func::{with_ctx, FnCtx};
 use crate::primitive::Primitive;
 use crate::val::{AsVal, Val};
 use crate::var::Var;
-use crate::{for_all_primitives, map_ident};
+use crate::{for_all_floats, for_all_integers, map_ident};
 
 macro_rules! make_arithmetic_traits {
     ($($name:ident $(,)?)*) => {
@@ -80,9 +80,21 @@ macro_rules! impl_unsigned {
     };
 }
 
-for_all_primitives!(impl_common);
+macro_rules! impl_float {
+    ($ty:ident) => {
+        impl_arithmetic!($ty:
+            IntAdd => |ctx, lhs, rhs| ctx.builder().ins().fadd(lhs, rhs),
+            IntSub => |ctx, lhs, rhs| ctx.builder().ins().fsub(lhs, rhs),
+            IntMul => |ctx, lhs, rhs| ctx.builder().ins().fmul(lhs, rhs),
+            IntDiv => |ctx, lhs, rhs| ctx.builder().ins().fdiv(lhs, rhs),
+        );
+    };
+}
+
+for_all_integers!(impl_common);
 map_ident!(impl_signed: i8, i16, i32, i64, isize);
 map_ident!(impl_unsigned: u8, u16, u32, u64, usize);
+for_all_floats!(impl_float);
 
 impl BitAnd<Val<bool>> for Val<bool> {
     type Output = Val<bool>;
@@ -92,13 +104,20 @@ impl BitAnd<Val<bool>> for Val<bool> {
     }
 }
 
+impl BitOr<Val<bool>> for Val<bool> {
+    type Output = Val<bool>;
+
+    fn bitor(self, rhs: Val<bool>) -> Self::Output {
+        with_ctx(|ctx| Val::from_value(ctx.builder().ins().bor(self.value(), rhs.value())))
+    }
+}
+
 impl Not for Val<bool> {
     type Output = Val<bool>;
 
     fn not(self) -> Self::Output {
-        with_ctx(|ctx| {
-            Val::from_value(ctx.builder.ins().bnot(self.value))
-        })
+        // booleans are 0 or 1, `bnot` would turn 1 into another true value
+        with_ctx(|ctx| Val::from_value(ctx.builder.ins().bxor_imm(self.value, 1)))
     }
 }
 
@@ -113,7 +132,7 @@ macro_rules! impl_op {
                 fn $f(self, rhs: T) -> Self::Output {
                     with_ctx(|ctx| -> Val<T> {
                         let lhs = self.as_val(ctx);
-                        let rhs = ctx.builder().ins().iconst(T::ty(), rhs.to_i64());
+                        let rhs = rhs.materialize(ctx);
                         Val::from_value(T::perform(ctx, lhs.value(), rhs))
                     })
                 }
@@ -0,0 +1,58 @@
+use cranelift_jit::JITModule;
+use cranelift_module::{FuncId, Module};
+use cranelift_object::ObjectModule;
+
+use crate::error::LegoError;
+
+/// A cranelift [`Module`] a [`Ctx`](crate::ctx::Ctx) can emit functions to.
+///
+/// [`JITModule`] compiles functions in memory, to be called right away, while [`ObjectModule`]
+/// emits them to an object file, to be linked ahead of time.
+pub trait Backend: Module + Sized {
+    /// Whether the code runs in the current process, so that it can refer to addresses of the
+    /// host.
+    const IN_PROCESS: bool;
+
+    /// Make the functions defined so far ready for use.
+    fn finalize(&mut self) -> Result<(), LegoError>;
+
+    /// Address of the code of a finalized function, if it is loaded in memory.
+    fn function_address(&self, id: FuncId) -> Option<*const u8>;
+
+    /// Release the module, and the code it holds.
+    ///
+    /// # Safety
+    /// No pointer to code of the module may be used afterwards.
+    unsafe fn free(self);
+}
+
+impl Backend for JITModule {
+    const IN_PROCESS: bool = true;
+
+    fn finalize(&mut self) -> Result<(), LegoError> {
+        Ok(self.finalize_definitions()?)
+    }
+
+    fn function_address(&self, id: FuncId) -> Option<*const u8> {
+        Some(self.get_finalized_function(id))
+    }
+
+    unsafe fn free(self) {
+        self.free_memory();
+    }
+}
+
+impl Backend for ObjectModule {
+    const IN_PROCESS: bool = false;
+
+    fn finalize(&mut self) -> Result<(), LegoError> {
+        // relocations are resolved by the linker
+        Ok(())
+    }
+
+    fn function_address(&self, _id: FuncId) -> Option<*const u8> {
+        None
+    }
+
+    unsafe fn free(self) {}
+}
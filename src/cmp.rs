@@ -1,67 +1,316 @@
-use cranelift::prelude::{InstBuilder as _, IntCC};
+use cranelift::prelude::{FloatCC, InstBuilder as _, IntCC, Value};
 
-use crate::for_all_primitives;
-use crate::func::with_ctx;
+use crate::func::{with_ctx, FnCtx};
+use crate::primitive::Primitive;
 use crate::val::{AsVal, Val};
+use crate::var::Var;
+use crate::{for_all_floats, for_all_primitives, map_ident};
 
-macro_rules! cmp_var {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Primitives that can be compared in generated code.
+///
+/// The condition code is picked from the rust type: signed integers use signed comparisons,
+/// unsigned integers use unsigned comparisons and floats use ordered float comparisons.
+pub trait Comparable: Primitive {
+    fn compare(ctx: &mut FnCtx, op: CmpOp, lhs: Value, rhs: Value) -> Value;
+
+    /// Whether `min` and `max` should pick `rhs` over `lhs`, given that `rhs` compares `op`
+    /// to `lhs`.
+    fn pick_rhs(ctx: &mut FnCtx, op: CmpOp, lhs: Value, rhs: Value) -> Value {
+        Self::compare(ctx, op, rhs, lhs)
+    }
+
+    fn min(ctx: &mut FnCtx, lhs: Value, rhs: Value) -> Value {
+        let pick_rhs = Self::pick_rhs(ctx, CmpOp::Lt, lhs, rhs);
+        ctx.builder().ins().select(pick_rhs, rhs, lhs)
+    }
+
+    fn max(ctx: &mut FnCtx, lhs: Value, rhs: Value) -> Value {
+        let pick_rhs = Self::pick_rhs(ctx, CmpOp::Gt, lhs, rhs);
+        ctx.builder().ins().select(pick_rhs, rhs, lhs)
+    }
+}
+
+macro_rules! impl_comparable_int {
+    ($ty:ident, $lt:ident, $le:ident, $gt:ident, $ge:ident) => {
+        impl Comparable for $ty {
+            fn compare(ctx: &mut FnCtx, op: CmpOp, lhs: Value, rhs: Value) -> Value {
+                let cc = match op {
+                    CmpOp::Eq => IntCC::Equal,
+                    CmpOp::Ne => IntCC::NotEqual,
+                    CmpOp::Lt => IntCC::$lt,
+                    CmpOp::Le => IntCC::$le,
+                    CmpOp::Gt => IntCC::$gt,
+                    CmpOp::Ge => IntCC::$ge,
+                };
+                ctx.builder().ins().icmp(cc, lhs, rhs)
+            }
+        }
+    };
+}
+
+macro_rules! impl_comparable_signed {
+    ($ty:ident) => {
+        impl_comparable_int!(
+            $ty,
+            SignedLessThan,
+            SignedLessThanOrEqual,
+            SignedGreaterThan,
+            SignedGreaterThanOrEqual
+        );
+    };
+}
+
+macro_rules! impl_comparable_unsigned {
+    ($ty:ident) => {
+        impl_comparable_int!(
+            $ty,
+            UnsignedLessThan,
+            UnsignedLessThanOrEqual,
+            UnsignedGreaterThan,
+            UnsignedGreaterThanOrEqual
+        );
+    };
+}
+
+macro_rules! impl_comparable_float {
     ($ty:ident) => {
-        impl Compare for Val<$ty> {
-            fn eq(self, other: Self) -> Val<bool> {
-                with_ctx(|ctx| {
-                    let lhs = self.as_val(ctx);
-                    let rhs = other.as_val(ctx);
-                    let val = ctx
-                        .builder()
-                        .ins()
-                        .icmp(IntCC::Equal, lhs.value(), rhs.value());
-                    Val::from_value(val)
-                })
+        impl Comparable for $ty {
+            fn compare(ctx: &mut FnCtx, op: CmpOp, lhs: Value, rhs: Value) -> Value {
+                // all comparisons are false if either side is NaN, except for Ne, like rust
+                let cc = match op {
+                    CmpOp::Eq => FloatCC::Equal,
+                    CmpOp::Ne => FloatCC::NotEqual,
+                    CmpOp::Lt => FloatCC::LessThan,
+                    CmpOp::Le => FloatCC::LessThanOrEqual,
+                    CmpOp::Gt => FloatCC::GreaterThan,
+                    CmpOp::Ge => FloatCC::GreaterThanOrEqual,
+                };
+                ctx.builder().ins().fcmp(cc, lhs, rhs)
             }
 
-            fn neq(self, other: Self) -> Val<bool> {
-                with_ctx(|ctx| {
-                    let lhs = self.as_val(ctx);
-                    let rhs = other.as_val(ctx);
-                    let val = ctx
-                        .builder()
-                        .ins()
-                        .icmp(IntCC::NotEqual, lhs.value(), rhs.value());
-                    Val::from_value(val)
-                })
+            // like rust, a NaN is only picked if both sides are NaN
+            fn pick_rhs(ctx: &mut FnCtx, op: CmpOp, lhs: Value, rhs: Value) -> Value {
+                let ordered = Self::compare(ctx, op, rhs, lhs);
+                let lhs_is_nan = ctx.builder().ins().fcmp(FloatCC::Unordered, lhs, lhs);
+                ctx.builder().ins().bor(ordered, lhs_is_nan)
             }
         }
     };
 }
 
-for_all_primitives!(cmp_var);
-
-// impl<T, U, P> Compare<&U> for &T
-// where
-//     T: AsVal<Ty = P>,
-//     U: AsVal<Ty = P>,
-//     P: Primitive,
-// {
-//     fn eq(self, other: &U) -> Val<bool> {
-//         with_ctx(|ctx| {
-//             let lhs = self.as_val(ctx);
-//             let rhs = other.as_val(ctx);
-//             let val = ctx.builder().ins().icmp(IntCC::Equal, lhs.value(), rhs.value());
-//             Val::from_value(val)
-//         })
-//     }
-//
-//     fn neq(self, other: &U) -> Val<bool> {
-//         with_ctx(|ctx| {
-//             let lhs = self.as_val(ctx);
-//             let rhs = other.as_val(ctx);
-//             let val = ctx.builder().ins().icmp(IntCC::NotEqual, lhs.value(), rhs.value());
-//             Val::from_value(val)
-//         })
-//     }
-// }
+map_ident!(impl_comparable_signed: i8, i16, i32, i64, isize);
+map_ident!(impl_comparable_unsigned: u8, u16, u32, u64, usize);
+for_all_floats!(impl_comparable_float);
+
+fn compare<T: Comparable>(
+    op: CmpOp,
+    lhs: &impl AsVal<Ty = T>,
+    rhs: &impl AsVal<Ty = T>,
+) -> Val<bool> {
+    with_ctx(|ctx| {
+        let lhs = lhs.as_val(ctx);
+        let rhs = rhs.as_val(ctx);
+        Val::from_value(T::compare(ctx, op, lhs.value(), rhs.value()))
+    })
+}
+
+macro_rules! impl_compare {
+    ($($ty:ident $(,)?)*) => {
+        $(
+            impl<T, R> Compare<R> for $ty<T>
+            where
+                T: Comparable,
+                R: AsVal<Ty = T>,
+            {
+                fn eq(self, other: R) -> Val<bool> {
+                    compare(CmpOp::Eq, &self, &other)
+                }
+
+                fn neq(self, other: R) -> Val<bool> {
+                    compare(CmpOp::Ne, &self, &other)
+                }
+
+                fn lt(self, other: R) -> Val<bool> {
+                    compare(CmpOp::Lt, &self, &other)
+                }
+
+                fn le(self, other: R) -> Val<bool> {
+                    compare(CmpOp::Le, &self, &other)
+                }
+
+                fn gt(self, other: R) -> Val<bool> {
+                    compare(CmpOp::Gt, &self, &other)
+                }
+
+                fn ge(self, other: R) -> Val<bool> {
+                    compare(CmpOp::Ge, &self, &other)
+                }
+            }
+        )*
+    };
+}
+
+impl_compare!(Val, Var);
 
 pub trait Compare<Rhs = Self> {
     fn eq(self, other: Rhs) -> Val<bool>;
     fn neq(self, other: Rhs) -> Val<bool>;
+    fn lt(self, other: Rhs) -> Val<bool>;
+    fn le(self, other: Rhs) -> Val<bool>;
+    fn gt(self, other: Rhs) -> Val<bool>;
+    fn ge(self, other: Rhs) -> Val<bool>;
+}
+
+macro_rules! impl_min_max {
+    ($($ty:ident $(,)?)*) => {
+        $(
+            impl<T: Comparable> $ty<T> {
+                /// The smallest of the two values, `self` if they are equal. Like rust, the
+                /// other value is returned if one of them is NaN.
+                pub fn min(self, other: impl AsVal<Ty = T>) -> Val<T> {
+                    with_ctx(|ctx| {
+                        let lhs = self.as_val(ctx);
+                        let rhs = other.as_val(ctx);
+                        Val::from_value(T::min(ctx, lhs.value(), rhs.value()))
+                    })
+                }
+
+                /// The largest of the two values, `self` if they are equal. Like rust, the
+                /// other value is returned if one of them is NaN.
+                pub fn max(self, other: impl AsVal<Ty = T>) -> Val<T> {
+                    with_ctx(|ctx| {
+                        let lhs = self.as_val(ctx);
+                        let rhs = other.as_val(ctx);
+                        Val::from_value(T::max(ctx, lhs.value(), rhs.value()))
+                    })
+                }
+
+                /// Restrict the value to `min..=max`. Unlike rust, `min <= max` is not checked.
+                pub fn clamp(self, min: impl AsVal<Ty = T>, max: impl AsVal<Ty = T>) -> Val<T> {
+                    self.max(min).min(max)
+                }
+            }
+        )*
+    };
 }
+
+impl_min_max!(Val, Var);
+
+/// Staged counterpart of [`PartialEq`], used by the `lego!` macro to lower `==` and `!=`.
+///
+/// Comparing two host values evaluates the comparison immediately and returns a `bool`, while
+/// comparing staged values emits the comparison in the function being built and returns a
+/// `Val<bool>`.
+pub trait StagedPartialEq<Rhs = Self> {
+    type Output;
+
+    fn staged_eq(&self, other: &Rhs) -> Self::Output;
+    fn staged_ne(&self, other: &Rhs) -> Self::Output;
+}
+
+/// Staged counterpart of [`PartialOrd`], used by the `lego!` macro to lower `<`, `<=`, `>`
+/// and `>=`.
+pub trait StagedPartialOrd<Rhs = Self>: StagedPartialEq<Rhs> {
+    fn staged_lt(&self, other: &Rhs) -> Self::Output;
+    fn staged_le(&self, other: &Rhs) -> Self::Output;
+    fn staged_gt(&self, other: &Rhs) -> Self::Output;
+    fn staged_ge(&self, other: &Rhs) -> Self::Output;
+}
+
+impl<T, U> StagedPartialEq<U> for T
+where
+    T: PartialEq<U>,
+{
+    type Output = bool;
+
+    fn staged_eq(&self, other: &U) -> bool {
+        self == other
+    }
+
+    fn staged_ne(&self, other: &U) -> bool {
+        self != other
+    }
+}
+
+impl<T, U> StagedPartialOrd<U> for T
+where
+    T: PartialOrd<U>,
+{
+    fn staged_lt(&self, other: &U) -> bool {
+        self < other
+    }
+
+    fn staged_le(&self, other: &U) -> bool {
+        self <= other
+    }
+
+    fn staged_gt(&self, other: &U) -> bool {
+        self > other
+    }
+
+    fn staged_ge(&self, other: &U) -> bool {
+        self >= other
+    }
+}
+
+macro_rules! impl_staged_cmp {
+    ($lhs:ty, $rhs:ty $(, $gen:ident)?) => {
+        impl<$($gen: Comparable)?> StagedPartialEq<$rhs> for $lhs {
+            type Output = Val<bool>;
+
+            fn staged_eq(&self, other: &$rhs) -> Val<bool> {
+                compare(CmpOp::Eq, self, other)
+            }
+
+            fn staged_ne(&self, other: &$rhs) -> Val<bool> {
+                compare(CmpOp::Ne, self, other)
+            }
+        }
+
+        impl<$($gen: Comparable)?> StagedPartialOrd<$rhs> for $lhs {
+            fn staged_lt(&self, other: &$rhs) -> Val<bool> {
+                compare(CmpOp::Lt, self, other)
+            }
+
+            fn staged_le(&self, other: &$rhs) -> Val<bool> {
+                compare(CmpOp::Le, self, other)
+            }
+
+            fn staged_gt(&self, other: &$rhs) -> Val<bool> {
+                compare(CmpOp::Gt, self, other)
+            }
+
+            fn staged_ge(&self, other: &$rhs) -> Val<bool> {
+                compare(CmpOp::Ge, self, other)
+            }
+        }
+    };
+}
+
+impl_staged_cmp!(Val<T>, Val<T>, T);
+impl_staged_cmp!(Val<T>, Var<T>, T);
+impl_staged_cmp!(Var<T>, Val<T>, T);
+impl_staged_cmp!(Var<T>, Var<T>, T);
+
+// Mixed host/staged comparisons can't be expressed generically over the host type without
+// conflicting with the host blanket impls, so we spell them out.
+macro_rules! impl_staged_cmp_host {
+    ($ty:ident) => {
+        impl_staged_cmp!(Val<$ty>, $ty);
+        impl_staged_cmp!(Var<$ty>, $ty);
+        impl_staged_cmp!($ty, Val<$ty>);
+        impl_staged_cmp!($ty, Var<$ty>);
+    };
+}
+
+for_all_primitives!(impl_staged_cmp_host);
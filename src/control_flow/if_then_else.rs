@@ -0,0 +1,129 @@
+use std::marker::PhantomData;
+
+use cranelift::prelude::{Block, InstBuilder};
+
+use crate::func::with_ctx;
+use crate::val::Val;
+
+use super::{jump_to_merge, BlockRet, ControlFlow, DeadValue, FlowControl};
+
+/// Context passed to the branches of an [`If`].
+pub struct IfCtx {
+    _priv: (),
+}
+
+impl FlowControl for IfCtx {}
+
+/// Staged `if`/`else` expression, as emitted by the `lego!` macro.
+///
+/// The branches are passed one after the other, so that they can both borrow the same state:
+/// ```ignore
+/// If::new(cond)
+///     .then(|ctx| ControlFlow::Break(a))
+///     .otherwise(|ctx| ControlFlow::Break(b))
+/// ```
+/// The condition is either a staged `Val<bool>`, or a host `bool`. In the latter case, only the
+/// taken branch is evaluated, and the branches can evaluate to host values.
+pub struct If<C> {
+    cond: C,
+}
+
+impl<C> If<C> {
+    pub fn new(cond: C) -> Self {
+        Self { cond }
+    }
+}
+
+impl If<bool> {
+    pub fn then<B, F>(self, f: F) -> HostThen<B>
+    where
+        F: FnOnce(&mut IfCtx) -> ControlFlow<B>,
+    {
+        HostThen {
+            then_val: self.cond.then(|| f(&mut IfCtx { _priv: () })),
+        }
+    }
+}
+
+pub struct HostThen<B> {
+    /// set if the `then` branch was taken
+    then_val: Option<ControlFlow<B>>,
+}
+
+impl<B: DeadValue> HostThen<B> {
+    pub fn otherwise<F>(self, f: F) -> B
+    where
+        F: FnOnce(&mut IfCtx) -> ControlFlow<B>,
+    {
+        let val = self
+            .then_val
+            .unwrap_or_else(|| f(&mut IfCtx { _priv: () }));
+        match val {
+            ControlFlow::Break(val) => val,
+            // the branch was terminated, what follows is dead code.
+            _ => B::dead(),
+        }
+    }
+}
+
+impl If<Val<bool>> {
+    pub fn then<B, F>(self, f: F) -> StagedThen<B>
+    where
+        F: FnOnce(&mut IfCtx) -> ControlFlow<B>,
+        B: BlockRet,
+    {
+        let cond = self.cond;
+        let [else_block, merge_block] = with_ctx(|ctx| {
+            let [then_block, else_block, merge_block] = ctx.create_blocks();
+            B::push_param_ty(ctx, merge_block);
+            let b = ctx.builder();
+            b.ins().brif(cond.value(), then_block, &[], else_block, &[]);
+            b.switch_to_block(then_block);
+            b.seal_block(then_block);
+            [else_block, merge_block]
+        });
+
+        let then_val = f(&mut IfCtx { _priv: () });
+        jump_to_merge(then_val, merge_block);
+
+        StagedThen {
+            else_block,
+            merge_block,
+            _pth: PhantomData,
+        }
+    }
+}
+
+pub struct StagedThen<B> {
+    else_block: Block,
+    merge_block: Block,
+    _pth: PhantomData<B>,
+}
+
+impl<B: BlockRet> StagedThen<B> {
+    pub fn otherwise<F>(self, f: F) -> B
+    where
+        F: FnOnce(&mut IfCtx) -> ControlFlow<B>,
+    {
+        let Self {
+            else_block,
+            merge_block,
+            ..
+        } = self;
+
+        with_ctx(|ctx| {
+            ctx.builder().switch_to_block(else_block);
+            ctx.builder().seal_block(else_block);
+        });
+
+        let else_val = f(&mut IfCtx { _priv: () });
+        jump_to_merge(else_val, merge_block);
+
+        with_ctx(|ctx| {
+            let b = ctx.builder();
+            b.switch_to_block(merge_block);
+            b.seal_block(merge_block);
+            B::read_from_ret(&mut b.block_params(merge_block).iter().copied())
+        })
+    }
+}
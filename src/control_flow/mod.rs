@@ -1,13 +1,163 @@
+use std::marker::PhantomData;
+
+use cranelift::prelude::types::{F32, F64};
 use cranelift::prelude::{Block, InstBuilder, Value};
 
-use crate::func::FnCtx;
+use crate::func::{with_ctx, FnCtx, FuncRet};
 use crate::primitive::Primitive;
-use crate::proxy::Ref;
+use crate::proxy::{Ref, RefMut};
+use crate::slice::{ArrayChunk, Slice};
 use crate::val::Val;
+use crate::for_all_primitives;
+
+use self::while_loop::LoopBreak;
 
+pub mod if_then_else;
+pub mod select;
+pub mod switch;
 mod then;
 pub mod while_loop;
 
+/// Outcome of a staged block, as seen by the host code building it.
+pub enum ControlFlow<B> {
+    /// The block ran to completion and evaluated to `B`.
+    Break(B),
+    /// The block returned from the function: it is terminated and nothing can be emitted in it
+    /// anymore.
+    Ret,
+    /// The block jumped back to the header of the enclosing loop.
+    Continue,
+    /// The block broke out of the enclosing loop.
+    Preempt,
+}
+
+/// A loop whose body is being emitted.
+pub(crate) struct LoopFrame {
+    pub(crate) header: Block,
+    pub(crate) exit: Block,
+    /// values passed to the header on `continue`
+    pub(crate) continue_args: Vec<Value>,
+}
+
+/// Early exits available from staged blocks, as emitted by the `lego!` macro for `return`,
+/// `break` and `continue`.
+pub trait FlowControl {
+    /// Return from the function being built.
+    fn ret<B>(&mut self, val: impl FuncRet) -> ControlFlow<B> {
+        emit_ret(val)
+    }
+
+    /// Jump out of the innermost enclosing loop `_loop`, which then evaluates to `val`.
+    fn break_loop<L: BlockRet, B>(&mut self, _loop: LoopBreak<L>, val: L) -> ControlFlow<B> {
+        with_ctx(|ctx| {
+            let mut args = Vec::new();
+            val.to_block_values(&mut args);
+            let exit = ctx.innermost_loop().exit;
+            let b = ctx.builder();
+            let expected = b.block_params(exit).iter().map(|v| b.func.dfg.value_type(*v));
+            assert!(
+                expected.eq(args.iter().map(|v| b.func.dfg.value_type(*v))),
+                "the value passed to `break` doesn't match the type of the loop"
+            );
+            b.ins().jump(exit, &args);
+        });
+        ControlFlow::Preempt
+    }
+
+    /// Jump back to the header of the innermost enclosing loop.
+    fn continue_loop<B>(&mut self) -> ControlFlow<B> {
+        with_ctx(|ctx| {
+            let LoopFrame {
+                header,
+                ref continue_args,
+                ..
+            } = *ctx.innermost_loop();
+            let args = continue_args.clone();
+            ctx.builder().ins().jump(header, &args);
+        });
+        ControlFlow::Continue
+    }
+}
+
+/// A branching condition: either a host `bool`, known at build time, or a staged `Val<bool>`.
+pub enum Cond {
+    Host(bool),
+    Staged(Val<bool>),
+}
+
+pub trait IntoCond {
+    fn into_cond(self) -> Cond;
+}
+
+impl IntoCond for bool {
+    fn into_cond(self) -> Cond {
+        Cond::Host(self)
+    }
+}
+
+impl IntoCond for Val<bool> {
+    fn into_cond(self) -> Cond {
+        Cond::Staged(self)
+    }
+}
+
+/// Emit a return from the function being built.
+pub(crate) fn emit_ret<B>(val: impl FuncRet) -> ControlFlow<B> {
+    with_ctx(|ctx| val.return_(ctx));
+    ControlFlow::Ret
+}
+
+/// Jump to the merge block if the branch wasn't already terminated.
+pub(crate) fn jump_to_merge<B: BlockRet>(val: ControlFlow<B>, merge_block: Block) {
+    if let ControlFlow::Break(val) = val {
+        with_ctx(|ctx| {
+            let mut params = Vec::new();
+            val.to_block_values(&mut params);
+            ctx.builder().ins().jump(merge_block, &params);
+        });
+    }
+}
+
+/// Switch to a fresh block with no predecessors, and return a value for it.
+///
+/// This is used to keep emitting code after a block was terminated, e.g after a return: the code
+/// that follows is dead, but it still needs a value to work with.
+pub(crate) fn dead_block<B: BlockRet>() -> B {
+    with_ctx(|ctx| {
+        let [block] = ctx.create_blocks();
+        B::push_param_ty(ctx, block);
+        ctx.builder().switch_to_block(block);
+        ctx.builder().seal_block(block);
+        B::read_from_ret(&mut ctx.builder().block_params(block).iter().copied())
+    })
+}
+
+/// Values that can be produced for dead code, e.g. the value of an `if` whose taken branch
+/// returned from the function. Producing one switches to an unreachable block.
+pub trait DeadValue {
+    fn dead() -> Self;
+}
+
+impl<T: BlockRet> DeadValue for T {
+    fn dead() -> Self {
+        dead_block()
+    }
+}
+
+macro_rules! impl_dead_value_host {
+    ($ty:ident) => {
+        impl DeadValue for $ty {
+            fn dead() -> Self {
+                dead_block::<()>();
+                Default::default()
+            }
+        }
+    };
+}
+
+for_all_primitives!(impl_dead_value_host);
+impl_dead_value_host!(bool);
+
 pub trait BlockRet {
     /// push param ty for the passed block
     fn push_param_ty(ctx: &mut FnCtx, block: Block);
@@ -40,7 +190,13 @@ impl<T: Primitive> BlockRet for Val<T> {
     }
 
     fn null(ctx: &mut FnCtx, out: &mut Vec<Value>) {
-        out.push(ctx.builder().ins().iconst(T::ty(), 0));
+        let ty = T::ty();
+        let null = match ty {
+            F32 => ctx.builder().ins().f32const(0.0),
+            F64 => ctx.builder().ins().f64const(0.0),
+            _ => ctx.builder().ins().iconst(ty, 0),
+        };
+        out.push(null);
     }
 }
 
@@ -63,6 +219,71 @@ impl<T> BlockRet for Ref<'_, T> {
     }
 }
 
+impl<T> BlockRet for RefMut<'_, T> {
+    fn push_param_ty(ctx: &mut FnCtx, block: Block) {
+        ctx.builder().append_block_param(block, <*mut T>::ty());
+    }
+
+    fn read_from_ret(block_params: &mut impl Iterator<Item = Value>) -> Self {
+        let addr = Val::from_value(block_params.next().unwrap());
+        RefMut::new(addr)
+    }
+
+    fn to_block_values(&self, out: &mut Vec<Value>) {
+        out.push(self.addr.value());
+    }
+
+    fn null(ctx: &mut FnCtx, out: &mut Vec<Value>) {
+        out.push(ctx.builder().ins().iconst(<*mut T>::ty(), 0));
+    }
+}
+
+impl<T> BlockRet for Slice<'_, T> {
+    fn push_param_ty(ctx: &mut FnCtx, block: Block) {
+        ctx.builder().append_block_param(block, <*const T>::ty());
+        ctx.builder().append_block_param(block, usize::ty());
+    }
+
+    fn read_from_ret(block_params: &mut impl Iterator<Item = Value>) -> Self {
+        Slice {
+            base: Val::from_value(block_params.next().unwrap()),
+            len: Val::from_value(block_params.next().unwrap()),
+            _p: PhantomData,
+        }
+    }
+
+    fn to_block_values(&self, out: &mut Vec<Value>) {
+        out.push(self.base.value());
+        out.push(self.len.value());
+    }
+
+    fn null(ctx: &mut FnCtx, out: &mut Vec<Value>) {
+        out.push(ctx.builder().ins().iconst(<*const T>::ty(), 0));
+        out.push(ctx.builder().ins().iconst(usize::ty(), 0));
+    }
+}
+
+impl<T, const N: usize> BlockRet for ArrayChunk<'_, T, N> {
+    fn push_param_ty(ctx: &mut FnCtx, block: Block) {
+        ctx.builder().append_block_param(block, <*const T>::ty());
+    }
+
+    fn read_from_ret(block_params: &mut impl Iterator<Item = Value>) -> Self {
+        ArrayChunk {
+            base: Val::from_value(block_params.next().unwrap()),
+            _p: PhantomData,
+        }
+    }
+
+    fn to_block_values(&self, out: &mut Vec<Value>) {
+        out.push(self.base.value());
+    }
+
+    fn null(ctx: &mut FnCtx, out: &mut Vec<Value>) {
+        out.push(ctx.builder().ins().iconst(<*const T>::ty(), 0));
+    }
+}
+
 impl<T, U> BlockRet for (T, U)
     where
     T: BlockRet, U: BlockRet,
@@ -0,0 +1,42 @@
+use cranelift::prelude::InstBuilder;
+
+use crate::func::with_ctx;
+use crate::val::Val;
+
+use super::{BlockRet, Cond, IntoCond};
+
+/// Evaluate to `a` if `cond` is true, and to `b` otherwise, without branching.
+///
+/// Contrary to [`If`](super::if_then_else::If), both values are always computed: each of the
+/// values making up `B` is lowered to a cranelift `select`, that compiles to a conditional move.
+/// This is cheaper than a branch when the values are cheap to compute, and the condition is hard
+/// to predict.
+///
+/// If the condition is a host `bool`, the value is picked at build time.
+pub fn select<B: BlockRet>(cond: impl IntoCond, a: B, b: B) -> B {
+    let cond = match cond.into_cond() {
+        Cond::Host(true) => return a,
+        Cond::Host(false) => return b,
+        Cond::Staged(cond) => cond,
+    };
+
+    let mut a_vals = Vec::new();
+    a.to_block_values(&mut a_vals);
+    let mut b_vals = Vec::new();
+    b.to_block_values(&mut b_vals);
+
+    with_ctx(|ctx| {
+        let mut selected = a_vals
+            .into_iter()
+            .zip(b_vals)
+            .map(|(a, b)| ctx.builder().ins().select(cond.value(), a, b));
+        B::read_from_ret(&mut selected)
+    })
+}
+
+impl Val<bool> {
+    /// Branchless counterpart of [`Val::then`], see [`select`].
+    pub fn select<B: BlockRet>(self, a: B, b: B) -> B {
+        select(self, a, b)
+    }
+}
@@ -0,0 +1,262 @@
+use std::marker::PhantomData;
+
+use cranelift::prelude::{Block, InstBuilder};
+use cranelift_frontend::Switch;
+
+use crate::func::with_ctx;
+use crate::primitive::Integer;
+use crate::val::{AsVal, Val};
+use crate::var::Var;
+
+use super::{jump_to_merge, BlockRet, ControlFlow, DeadValue, FlowControl};
+
+/// Context passed to the arms of a [`Match`].
+pub struct MatchCtx {
+    _priv: (),
+}
+
+impl FlowControl for MatchCtx {}
+
+/// Staged `match` on an integer, as emitted by the `lego!` macro for a `match` marked
+/// `#[staged]`:
+/// ```ignore
+/// #[staged]
+/// match opcode {
+///     0 => a,
+///     OP_ADD | OP_SUB => b,
+///     _ => c,
+/// }
+/// ```
+///
+/// Arms are passed one after the other, and the match is closed by the default arm:
+/// ```ignore
+/// Match::new(opcode)
+///     .case(0, |ctx| ControlFlow::Break(a))
+///     .cases([1, 2], |ctx| ControlFlow::Break(b))
+///     .default(|ctx| ControlFlow::Break(c))
+/// ```
+/// Matching on a staged value lowers to a cranelift `Switch`, which emits a jump table or a
+/// search tree depending on the density of the cases. The value of the taken arm is passed to a
+/// join block. Matching on a host integer only evaluates the matching arm.
+///
+/// As with rust's `match`, the first arm matching a value wins.
+pub struct Match<S> {
+    scrutinee: S,
+}
+
+impl<S> Match<S> {
+    pub fn new(scrutinee: S) -> Self {
+        Self { scrutinee }
+    }
+}
+
+impl<T: Integer> Match<T> {
+    pub fn case<B, F>(self, val: T, f: F) -> HostMatch<T, B>
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        self.cases([val], f)
+    }
+
+    pub fn cases<B, F>(self, vals: impl IntoIterator<Item = T>, f: F) -> HostMatch<T, B>
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        HostMatch {
+            scrutinee: self.scrutinee,
+            arm_val: None,
+        }
+        .cases(vals, f)
+    }
+
+    pub fn default<B, F>(self, f: F) -> B
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+        B: DeadValue,
+    {
+        HostMatch {
+            scrutinee: self.scrutinee,
+            arm_val: None,
+        }
+        .default(f)
+    }
+}
+
+pub struct HostMatch<T, B> {
+    scrutinee: T,
+    /// set once an arm was taken
+    arm_val: Option<ControlFlow<B>>,
+}
+
+impl<T: Integer, B> HostMatch<T, B> {
+    pub fn case<F>(self, val: T, f: F) -> Self
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        self.cases([val], f)
+    }
+
+    pub fn cases<F>(mut self, vals: impl IntoIterator<Item = T>, f: F) -> Self
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        if self.arm_val.is_none() && vals.into_iter().any(|v| v == self.scrutinee) {
+            self.arm_val = Some(f(&mut MatchCtx { _priv: () }));
+        }
+
+        self
+    }
+
+    pub fn default<F>(self, f: F) -> B
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+        B: DeadValue,
+    {
+        let val = self
+            .arm_val
+            .unwrap_or_else(|| f(&mut MatchCtx { _priv: () }));
+        match val {
+            ControlFlow::Break(val) => val,
+            // the arm was terminated, what follows is dead code.
+            _ => B::dead(),
+        }
+    }
+}
+
+macro_rules! impl_staged_match {
+    ($($ty:ident $(,)?)*) => {
+        $(
+            impl<T: Integer> Match<$ty<T>> {
+                pub fn case<B, F>(self, val: T, f: F) -> StagedMatch<T, B>
+                where
+                    F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+                    B: BlockRet,
+                {
+                    self.cases([val], f)
+                }
+
+                pub fn cases<B, F>(self, vals: impl IntoIterator<Item = T>, f: F) -> StagedMatch<T, B>
+                where
+                    F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+                    B: BlockRet,
+                {
+                    StagedMatch::new(&self.scrutinee).cases(vals, f)
+                }
+
+                pub fn default<B, F>(self, f: F) -> B
+                where
+                    F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+                    B: BlockRet,
+                {
+                    StagedMatch::new(&self.scrutinee).default(f)
+                }
+            }
+        )*
+    };
+}
+
+impl_staged_match!(Val, Var);
+
+pub struct StagedMatch<T, B> {
+    scrutinee: Val<T>,
+    switch: Switch,
+    /// block the switch is emitted in, once all the arms are known
+    dispatch_block: Block,
+    merge_block: Block,
+    arm_blocks: Vec<Block>,
+    _pth: PhantomData<B>,
+}
+
+impl<T: Integer, B: BlockRet> StagedMatch<T, B> {
+    fn new(scrutinee: &impl AsVal<Ty = T>) -> Self {
+        with_ctx(|ctx| {
+            let scrutinee = scrutinee.as_val(ctx);
+            let [dispatch_block, merge_block] = ctx.create_blocks();
+            B::push_param_ty(ctx, merge_block);
+            let b = ctx.builder();
+            b.ins().jump(dispatch_block, &[]);
+            b.seal_block(dispatch_block);
+
+            Self {
+                scrutinee,
+                switch: Switch::new(),
+                dispatch_block,
+                merge_block,
+                arm_blocks: Vec::new(),
+                _pth: PhantomData,
+            }
+        })
+    }
+
+    pub fn case<F>(self, val: T, f: F) -> Self
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        self.cases([val], f)
+    }
+
+    pub fn cases<F>(mut self, vals: impl IntoIterator<Item = T>, f: F) -> Self
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        let arm_block = with_ctx(|ctx| {
+            let [arm_block] = ctx.create_blocks();
+            for val in vals {
+                let entry = switch_entry(val);
+                // values already matched by a previous arm are shadowed
+                if !self.switch.entries().contains_key(&entry) {
+                    self.switch.set_entry(entry, arm_block);
+                }
+            }
+            ctx.builder().switch_to_block(arm_block);
+            arm_block
+        });
+
+        self.arm_blocks.push(arm_block);
+        let arm_val = f(&mut MatchCtx { _priv: () });
+        jump_to_merge(arm_val, self.merge_block);
+
+        self
+    }
+
+    pub fn default<F>(self, f: F) -> B
+    where
+        F: FnOnce(&mut MatchCtx) -> ControlFlow<B>,
+    {
+        let default_block = with_ctx(|ctx| {
+            let [default_block] = ctx.create_blocks();
+            ctx.builder().switch_to_block(default_block);
+            default_block
+        });
+
+        let default_val = f(&mut MatchCtx { _priv: () });
+        jump_to_merge(default_val, self.merge_block);
+
+        let Self {
+            scrutinee,
+            switch,
+            dispatch_block,
+            merge_block,
+            arm_blocks,
+            ..
+        } = self;
+
+        with_ctx(|ctx| {
+            let b = ctx.builder();
+            b.switch_to_block(dispatch_block);
+            switch.emit(b, scrutinee.value(), default_block);
+            // the switch was emitted, all the arms now have their predecessors
+            arm_blocks.iter().for_each(|block| b.seal_block(*block));
+            b.seal_block(default_block);
+            b.switch_to_block(merge_block);
+            b.seal_block(merge_block);
+            B::read_from_ret(&mut b.block_params(merge_block).iter().copied())
+        })
+    }
+}
+
+/// The switch operates on the unsigned representation of the value, truncated to its type.
+fn switch_entry<T: Integer>(val: T) -> u128 {
+    let bits = T::ty().bits();
+    (val.to_i64() as u64 & (u64::MAX >> (64 - bits))) as u128
+}
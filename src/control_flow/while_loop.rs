@@ -1,57 +1,186 @@
-// use cranelift::prelude::InstBuilder;
-//
-// use crate::{func::with_ctx, val::Val};
-//
-// use super::BlockRet;
-
-// pub fn do_while2<C, B, BR>(param: BR, f: C) -> BR
-// where
-//     C: FnOnce(BR) -> (Val<bool>, B),
-//     B: FnOnce(BR) -> BR,
-//     BR: BlockRet,
-// {
-//     let mut params = Vec::new();
-//     let [header_block, body_block, exit_block] = with_ctx(|ctx| {
-//         let [header_block, body_block, exit_block] = ctx.create_blocks();
-//
-//         BR::push_param_ty(ctx, exit_block);
-//         BR::push_param_ty(ctx, body_block);
-//         BR::push_param_ty(ctx, header_block);
-//         let builder = ctx.builder();
-//         param.to_block_values(&mut params);
-//         builder.ins().jump(header_block, &params);
-//         params.clear();
-//         builder.switch_to_block(header_block);
-//         [header_block, body_block, exit_block]
-//     });
-//
-//     let (header_val, body_fn) = f(param);
-//
-//     let param = with_ctx(|ctx| {
-//         let ret = BR::read_from_ret(&mut ctx.builder.block_params(header_block).iter().copied());
-//         let builder = ctx.builder();
-//         ret.to_block_values(&mut params);
-//         builder
-//             .ins()
-//             .brif(header_val.value(), body_block, &params, exit_block, &params);
-//         params.clear();
-//         builder.switch_to_block(body_block);
-//         builder.seal_block(body_block);
-//         BR::read_from_ret(&mut ctx.builder.block_params(body_block).iter().copied())
-//     });
-//
-//     let body_val = (body_fn)(param);
-//
-//     with_ctx(|ctx| {
-//         // BR::jump_to(body_val, ctx, header_block);
-//         let builder = ctx.builder();
-//         body_val.to_block_values(&mut params);
-//         builder
-//             .ins()
-//             .jump(header_block, &params);
-//         builder.switch_to_block(exit_block);
-//         builder.seal_block(header_block);
-//         builder.seal_block(exit_block);
-//         BR::read_from_ret(&mut ctx.builder.block_params(exit_block).iter().copied())
-//     })
-// }
+use std::marker::PhantomData;
+
+use cranelift::prelude::{Block, InstBuilder};
+
+use crate::func::with_ctx;
+
+use super::{BlockRet, Cond, ControlFlow, FlowControl, IntoCond, LoopFrame};
+
+/// Context passed to the body of a [`do_while`] or [`do_loop`].
+///
+/// The loop is driven by a host `while` loop that runs at most once:
+/// ```ignore
+/// do_while(|ctx| {
+///     while ctx.cond(|| cond) {
+///         // body
+///     }
+///     ControlFlow::Break(())
+/// })
+/// ```
+/// The first call to `cond` emits the loop header and branches to the body, the second one
+/// closes the loop.
+pub struct WhileCtx<B> {
+    state: WhileState,
+    _p: PhantomData<fn() -> B>,
+}
+
+/// The loop `break` jumps out of, which evaluates to `B`. See [`FlowControl::break_loop`].
+pub struct LoopBreak<B>(PhantomData<fn() -> B>);
+
+impl<B> Clone for LoopBreak<B> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B> Copy for LoopBreak<B> {}
+
+impl<B> LoopBreak<B> {
+    /// The innermost enclosing loop, whatever it evaluates to, e.g. the loop of a
+    /// [`JIterator`](crate::prelude::JIterator). The value passed to `break` is only checked
+    /// against the loop when the break is emitted.
+    pub fn enclosing() -> Self {
+        LoopBreak(PhantomData)
+    }
+}
+
+enum WhileState {
+    Init,
+    Body { header_block: Block, exit_block: Block },
+    Done,
+}
+
+impl<B: BlockRet> WhileCtx<B> {
+    /// The loop, for `break` to check the values it is passed against `B` at compile time.
+    pub fn loop_break(&self) -> LoopBreak<B> {
+        LoopBreak(PhantomData)
+    }
+
+    pub fn cond<C: IntoCond>(&mut self, f: impl FnOnce() -> C) -> bool {
+        match self.state {
+            WhileState::Init => {
+                let [header_block, body_block, exit_block] = with_ctx(|ctx| {
+                    let [header_block, body_block, exit_block] = ctx.create_blocks();
+                    B::push_param_ty(ctx, exit_block);
+                    ctx.builder().ins().jump(header_block, &[]);
+                    ctx.builder().switch_to_block(header_block);
+                    [header_block, body_block, exit_block]
+                });
+
+                let cond = f().into_cond();
+
+                self.state = WhileState::Body {
+                    header_block,
+                    exit_block,
+                };
+
+                let enter = with_ctx(|ctx| {
+                    ctx.loops.push(LoopFrame {
+                        header: header_block,
+                        exit: exit_block,
+                        continue_args: Vec::new(),
+                    });
+
+                    let b = ctx.builder();
+                    match cond {
+                        Cond::Staged(cond) => {
+                            b.ins()
+                                .brif(cond.value(), body_block, &[], exit_block, &[]);
+                        }
+                        Cond::Host(true) => {
+                            b.ins().jump(body_block, &[]);
+                        }
+                        // the body is never evaluated, don't bother emitting it
+                        Cond::Host(false) => {
+                            b.ins().jump(exit_block, &[]);
+                            return false;
+                        }
+                    }
+                    b.switch_to_block(body_block);
+                    b.seal_block(body_block);
+                    true
+                });
+
+                if !enter {
+                    self.close();
+                }
+
+                enter
+            }
+            WhileState::Body { header_block, .. } => {
+                with_ctx(|ctx| {
+                    ctx.builder().ins().jump(header_block, &[]);
+                });
+                self.close();
+                false
+            }
+            WhileState::Done => false,
+        }
+    }
+
+    /// Seal the loop blocks and continue emitting after the loop. The current block must already
+    /// be terminated.
+    fn close(&mut self) {
+        if let WhileState::Body {
+            header_block,
+            exit_block,
+        } = self.state
+        {
+            with_ctx(|ctx| {
+                ctx.loops.pop();
+                let b = ctx.builder();
+                b.seal_block(header_block);
+                b.switch_to_block(exit_block);
+                b.seal_block(exit_block);
+            });
+        }
+
+        self.state = WhileState::Done;
+    }
+}
+
+impl<B> FlowControl for WhileCtx<B> {}
+
+pub fn do_while<F>(f: F)
+where
+    F: FnOnce(&mut WhileCtx<()>) -> ControlFlow<()>,
+{
+    do_loop(f)
+}
+
+/// A loop evaluating to the value passed to `break`.
+///
+/// The condition is usually `true`, as for rust's `loop`: exiting the loop because the condition
+/// is false only works if `B` is `()`. The values passed to `break` must be `B`:
+/// ```compile_fail
+/// # use lego::prelude::*;
+/// # let mut ctx = Ctx::new();
+/// ctx.func::<i32, i32>(|_| lego!({
+///     let x: Val<i32> = loop {
+///         break Val::new(1i64);
+///     };
+///     x
+/// }));
+/// ```
+pub fn do_loop<B, F>(f: F) -> B
+where
+    F: FnOnce(&mut WhileCtx<B>) -> ControlFlow<()>,
+    B: BlockRet,
+{
+    let mut while_ctx = WhileCtx {
+        state: WhileState::Init,
+        _p: PhantomData,
+    };
+
+    // If the body was terminated, by a return, break or continue, the host loop was exited
+    // before the loop could be closed.
+    let _ = f(&mut while_ctx);
+    while_ctx.close();
+
+    // we are now in the exit block
+    with_ctx(|ctx| {
+        let b = ctx.builder();
+        let exit_block = b.current_block().unwrap();
+        B::read_from_ret(&mut b.block_params(exit_block).iter().copied())
+    })
+}
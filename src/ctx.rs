@@ -1,40 +1,156 @@
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use cranelift::prelude::Configurable as _;
-use cranelift_codegen::settings;
+use cranelift::prelude::{Signature, TrapCode};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, SetError};
 use cranelift_frontend::FunctionBuilderContext;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::Module;
+use cranelift_module::{FuncId, FuncOrDataId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
 
-use crate::func::{CompiledFunc, Func, Params, Results};
+use crate::backend::Backend;
+use crate::error::LegoError;
+use crate::func::{CompiledFunc, Func, PanicSlot, Params, Results};
 
-pub struct Ctx {
+/// A compilation context.
+///
+/// By default, functions are JIT compiled. The code of the compiled functions lives as long as
+/// the context: it is freed when the context is dropped or [reset](Ctx::reset).
+/// [`CompiledFunc`]s borrow the context, so that they can't outlive their code.
+///
+/// A context built with [`CtxBuilder::build_object`] emits the functions to an object file
+/// instead, see [`Ctx::emit`].
+pub struct Ctx<M: Backend = JITModule> {
     pub(crate) fn_builder_ctx: FunctionBuilderContext,
-    pub(crate) module: JITModule,
+    /// only taken out on drop, to free its memory
+    pub(crate) module: ManuallyDrop<M>,
     pub(crate) ctx: cranelift::prelude::codegen::Context,
+    /// unique id of `module`, to check that functions belong to it
+    pub(crate) module_id: u64,
+    isa: OwnedTargetIsa,
+    /// host functions that can be imported by name
+    host_fns: Vec<NamedHostFn>,
+    /// keep the IR and disassembly of compiled functions
+    pub(crate) disasm: bool,
+    pub(crate) func_infos: HashMap<FuncId, FuncInfo>,
+    /// functions declared with [`Ctx::declare_func`] whose body wasn't defined yet
+    pub(crate) undefined_funcs: HashSet<FuncId>,
+    /// environments of the host closures called by the compiled code, dropped with the code
+    pub(crate) host_envs: Vec<Box<dyn Any>>,
+    /// boxed, so that its address doesn't change when the context moves
+    pub(crate) panic_slot: Box<PanicSlot>,
+    /// offsets of the trapping instructions of the functions not finalized yet
+    pub(crate) pending_traps: Vec<(FuncId, Vec<(u32, TrapCode)>)>,
+    /// addresses of the trapping instructions of the finalized functions, sorted
+    pub(crate) trap_sites: Vec<(usize, TrapCode)>,
+}
+
+/// What was generated for a compiled function, see [`Ctx::inspect`].
+#[derive(Debug, Clone)]
+pub struct FuncInfo {
+    pub(crate) ir: Option<String>,
+    pub(crate) disasm: Option<String>,
+    pub(crate) code_size: usize,
+}
+
+impl FuncInfo {
+    /// The cranelift IR of the function, before optimizations. Only kept if disassembly was
+    /// enabled with [`CtxBuilder::disasm`].
+    pub fn ir(&self) -> Option<&str> {
+        self.ir.as_deref()
+    }
+
+    /// The disassembly of the machine code (vcode). Only kept if disassembly was enabled with
+    /// [`CtxBuilder::disasm`].
+    pub fn disasm(&self) -> Option<&str> {
+        self.disasm.as_deref()
+    }
+
+    /// Size of the machine code, in bytes.
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
 }
 
-#[derive(Default)]
 pub struct CtxBuilder {
     registered_functions: Vec<NamedHostFn>,
+    opt_level: OptLevel,
+    verifier: bool,
+    probestack: bool,
+    disasm: bool,
+    flags: Vec<(String, String)>,
+    isa_flags: Vec<(String, String)>,
+}
+
+impl Default for CtxBuilder {
+    fn default() -> Self {
+        Self {
+            registered_functions: Vec::new(),
+            opt_level: OptLevel::None,
+            verifier: true,
+            probestack: false,
+            disasm: false,
+            flags: Vec::new(),
+            isa_flags: Vec::new(),
+        }
+    }
+}
+
+/// Optimization level of the generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// No optimizations, for the fastest compilation.
+    #[default]
+    None,
+    /// Optimize for speed.
+    Speed,
+    /// Optimize for speed and code size.
+    SpeedAndSize,
+}
+
+impl OptLevel {
+    fn as_flag(self) -> &'static str {
+        match self {
+            OptLevel::None => "none",
+            OptLevel::Speed => "speed",
+            OptLevel::SpeedAndSize => "speed_and_size",
+        }
+    }
 }
 
 #[doc(hidden)]
 pub struct NamedHostFn {
     pub name: &'static str,
     pub ptr: *const u8,
+    pub signature: fn(&mut Signature),
 }
 
+/// Name `extern "C"` functions, to register them with [`CtxBuilder::register_host_functions`].
+///
+/// ```ignore
+/// extern "C" fn add(a: u64, b: u64) -> u64 {
+///     a + b
+/// }
+///
+/// let ctx = Ctx::builder()
+///     .register_host_functions(host_fns!(add => extern "C" fn(u64, u64) -> u64))
+///     .build();
+/// ```
 #[macro_export]
 macro_rules! host_fns {
     ($($name:expr => $as:ty $(,)?)*) => {
         {
-            use $crate::func::HostFn;
+            use $crate::prelude::HostFnPtr;
             [
-                $($crate::ctx::NamedHostFn {
+                $($crate::prelude::NamedHostFn {
                     name: stringify!($name),
                     ptr: ($name as $as).to_fn_ptr(),
+                    signature: <$as as HostFnPtr>::signature,
                 }),*
             ]
         }
@@ -42,38 +158,166 @@ macro_rules! host_fns {
 }
 
 impl CtxBuilder {
-    pub fn register_host_functions(&mut self, f: impl IntoIterator<Item = NamedHostFn>) {
+    /// Register host functions, created with [`host_fns!`](crate::host_fns), that the generated
+    /// code can call by name. See [`Ctx::import_func`].
+    pub fn register_host_functions(mut self, f: impl IntoIterator<Item = NamedHostFn>) -> Self {
         self.registered_functions.extend(f);
+        self
     }
 
+    /// Set the optimization level, defaults to [`OptLevel::None`].
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// Run the cranelift IR verifier on the generated functions. This is enabled by default.
+    pub fn verifier(mut self, enable: bool) -> Self {
+        self.verifier = enable;
+        self
+    }
+
+    /// Emit stack probes in functions with large stack frames. This is disabled by default.
+    pub fn probestack(mut self, enable: bool) -> Self {
+        self.probestack = enable;
+        self
+    }
+
+    /// Keep the IR and the disassembly of the compiled functions, to be retrieved with
+    /// [`Ctx::inspect`]. This is disabled by default, as it slows down compilation.
+    pub fn disasm(mut self, enable: bool) -> Self {
+        self.disasm = enable;
+        self
+    }
+
+    /// Set an arbitrary cranelift setting, e.g. `("regalloc_algorithm", "single_pass")`.
+    ///
+    /// Settings are applied after the ones set by the other methods of the builder, and override
+    /// them. Invalid settings make [`CtxBuilder::try_build`] fail.
+    pub fn flag(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.flags.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set an ISA specific setting, e.g. `("has_avx2", "true")` on x86_64.
+    ///
+    /// The ISA flags start from the features of the host, as detected by `cranelift_native`.
+    /// Invalid settings make [`CtxBuilder::try_build`] fail.
+    pub fn isa_flag(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.isa_flags.push((name.into(), value.into()));
+        self
+    }
+
+    /// Build the context, panicking on error. See [`CtxBuilder::try_build`].
     pub fn build(self) -> Ctx {
+        self.try_build().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_build(self) -> Result<Ctx, LegoError> {
+        // the code is called from where it was compiled, it doesn't need to be relocatable
+        let isa = self.isa(false)?;
+        let module = jit_module(isa.clone(), &self.registered_functions);
+
+        Ok(Ctx::with_module(
+            module,
+            isa,
+            self.registered_functions,
+            self.disasm,
+        ))
+    }
+
+    /// Build a context emitting functions to an object file named `name`, panicking on error.
+    /// See [`CtxBuilder::try_build_object`].
+    pub fn build_object(self, name: &str) -> Ctx<ObjectModule> {
+        self.try_build_object(name)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Build a context emitting functions to an object file named `name`, for the host machine.
+    ///
+    /// Imported host functions are left as undefined symbols, to be resolved when linking the
+    /// object. Host closures can't be called: defining a function calling one fails with
+    /// [`LegoError::HostClosureInObject`].
+    pub fn try_build_object(self, name: &str) -> Result<Ctx<ObjectModule>, LegoError> {
+        let isa = self.isa(true)?;
+        let module = object_module(isa.clone(), name)?;
+
+        Ok(Ctx::with_module(
+            module,
+            isa,
+            self.registered_functions,
+            self.disasm,
+        ))
+    }
+
+    fn isa(&self, is_pic: bool) -> Result<OwnedTargetIsa, LegoError> {
         let mut flag_builder = settings::builder();
         flag_builder.set("use_colocated_libcalls", "false").unwrap();
-        flag_builder.set("is_pic", "false").unwrap();
-        // flag_builder.set("opt_level", "speed").unwrap();
-        flag_builder.set("opt_level", "none").unwrap();
-        let isa_builder = cranelift_native::builder().unwrap_or_else(|msg| {
-            panic!("host machine is not supported: {}", msg);
-        });
-        let isa = isa_builder
-            .finish(settings::Flags::new(flag_builder))
+        flag_builder.set("is_pic", &is_pic.to_string()).unwrap();
+        flag_builder
+            .set("opt_level", self.opt_level.as_flag())
+            .unwrap();
+        flag_builder
+            .set("enable_verifier", &self.verifier.to_string())
+            .unwrap();
+        flag_builder
+            .set("enable_probestack", &self.probestack.to_string())
             .unwrap();
-        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        if self.probestack {
+            // the outlined probe is a libcall the JIT doesn't provide
+            flag_builder.set("probestack_strategy", "inline").unwrap();
+        }
+        for (name, value) in &self.flags {
+            flag_builder
+                .set(name, value)
+                .map_err(|error| invalid_setting(name, value, error))?;
+        }
 
-        let mut host_fn_map = HashMap::with_capacity(self.registered_functions.len());
-        for NamedHostFn { name, ptr } in self.registered_functions {
-            builder.symbol(name, ptr);
-            host_fn_map.insert(ptr as usize, name);
+        let mut isa_builder =
+            cranelift_native::builder().map_err(|msg| LegoError::UnsupportedHost(msg.into()))?;
+        for (name, value) in &self.isa_flags {
+            isa_builder
+                .set(name, value)
+                .map_err(|error| invalid_setting(name, value, error))?;
         }
+        isa_builder
+            .finish(settings::Flags::new(flag_builder))
+            .map_err(|error| LegoError::Codegen {
+                error: Box::new(error),
+                ir: None,
+            })
+    }
+}
 
-        let module = JITModule::new(builder);
+fn jit_module(isa: OwnedTargetIsa, host_fns: &[NamedHostFn]) -> JITModule {
+    let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+    builder.symbols(host_fns.iter().map(|f| (f.name, f.ptr)));
+    JITModule::new(builder)
+}
 
-        let mut ctx = module.make_context();
-        ctx.want_disasm = true;
-        Ctx {
-            fn_builder_ctx: FunctionBuilderContext::new(),
-            ctx,
-            module,
+fn object_module(isa: OwnedTargetIsa, name: &str) -> Result<ObjectModule, LegoError> {
+    let builder = ObjectBuilder::new(isa, name, cranelift_module::default_libcall_names())?;
+    Ok(ObjectModule::new(builder))
+}
+
+fn next_module_id() -> u64 {
+    static NEXT_MODULE_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_MODULE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn invalid_setting(name: &str, value: &str, error: SetError) -> LegoError {
+    LegoError::InvalidSetting {
+        name: name.into(),
+        value: value.into(),
+        error,
+    }
+}
+
+impl<M: Backend> Drop for Ctx<M> {
+    fn drop(&mut self) {
+        // safety: compiled functions borrow the context, none of them can be alive anymore
+        unsafe {
+            ManuallyDrop::take(&mut self.module).free();
         }
     }
 }
@@ -89,6 +333,97 @@ impl Ctx {
         Self::builder().build()
     }
 
+    pub fn builder() -> CtxBuilder {
+        CtxBuilder::default()
+    }
+
+    pub fn get_compiled_function<P, R>(&self, f: Func<P, R>) -> CompiledFunc<'_, P, R> {
+        self.try_get_compiled_function(f)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Get the compiled code of `f`, failing if some declared functions aren't defined yet: no
+    /// function is finalized until then.
+    pub fn try_get_compiled_function<P, R>(
+        &self,
+        f: Func<P, R>,
+    ) -> Result<CompiledFunc<'_, P, R>, LegoError> {
+        self.check_owned(&f);
+        self.check_defined()?;
+
+        let ptr = self.module.get_finalized_function(f.id());
+        Ok(CompiledFunc {
+            ptr,
+            panic_slot: &self.panic_slot,
+            trap_sites: &self.trap_sites,
+            _pth: PhantomData,
+        })
+    }
+
+    /// Free the code of all the functions compiled so far, and start over with an empty context.
+    ///
+    /// This is how memory is reclaimed when generating many short-lived functions: JIT memory
+    /// can't be freed per function. Functions created before the reset can't be used anymore.
+    pub fn reset(&mut self) {
+        let module = jit_module(self.isa.clone(), &self.host_fns);
+        let old = std::mem::replace(&mut *self.module, module);
+        // safety: compiled functions borrow the context, none of them can be alive anymore
+        unsafe {
+            old.free_memory();
+        }
+
+        self.module_id = next_module_id();
+        self.ctx = self.module.make_context();
+        self.func_infos.clear();
+        self.undefined_funcs.clear();
+        self.host_envs.clear();
+        self.pending_traps.clear();
+        self.trap_sites.clear();
+    }
+}
+
+impl<M: Backend> Ctx<M> {
+    fn with_module(
+        module: M,
+        isa: OwnedTargetIsa,
+        host_fns: Vec<NamedHostFn>,
+        disasm: bool,
+    ) -> Self {
+        let ctx = module.make_context();
+        Ctx {
+            fn_builder_ctx: FunctionBuilderContext::new(),
+            ctx,
+            module: ManuallyDrop::new(module),
+            module_id: next_module_id(),
+            isa,
+            host_fns,
+            disasm,
+            func_infos: HashMap::new(),
+            undefined_funcs: HashSet::new(),
+            host_envs: Vec::new(),
+            panic_slot: Box::default(),
+            pending_traps: Vec::new(),
+            trap_sites: Vec::new(),
+        }
+    }
+
+    /// Finalize the functions defined so far, and record where their code can trap.
+    pub(crate) fn finalize(&mut self) -> Result<(), LegoError> {
+        self.module.finalize()?;
+        for (id, traps) in self.pending_traps.drain(..) {
+            let Some(addr) = self.module.function_address(id) else {
+                continue;
+            };
+            self.trap_sites.extend(
+                traps
+                    .into_iter()
+                    .map(|(offset, code)| (addr as usize + offset as usize, code)),
+            );
+        }
+        self.trap_sites.sort_unstable_by_key(|(addr, _)| *addr);
+        Ok(())
+    }
+
     pub fn func<P, R>(&mut self, body: impl FnOnce(P::Values) -> R::Results) -> Func<P, R>
     where
         P: Params,
@@ -97,19 +432,176 @@ impl Ctx {
         Func::new(self, body)
     }
 
-    pub fn get_compiled_function<P, R>(&self, f: Func<P, R>) -> CompiledFunc<P, R> {
-        let ptr = self.module.get_finalized_function(f.id());
-        CompiledFunc {
-            ptr,
-            _pth: PhantomData,
+    /// Fallible version of [`Ctx::func`], returning an error if the function fails to compile.
+    pub fn try_func<P, R>(
+        &mut self,
+        body: impl FnOnce(P::Values) -> R::Results,
+    ) -> Result<Func<P, R>, LegoError>
+    where
+        P: Params,
+        R: Results,
+    {
+        Func::try_new(self, body)
+    }
+
+    /// Declare a function, to be defined later with [`Ctx::define_func`].
+    ///
+    /// The function can be called before it is defined, which allows for recursive and mutually
+    /// recursive functions, and can be looked up by name with [`Ctx::get_func`]. No function
+    /// can be compiled until all declared functions are defined.
+    pub fn declare_func<P, R>(&mut self, name: &str) -> Func<P, R>
+    where
+        P: Params,
+        R: Results,
+    {
+        self.try_declare_func(name)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_declare_func<P, R>(&mut self, name: &str) -> Result<Func<P, R>, LegoError>
+    where
+        P: Params,
+        R: Results,
+    {
+        let sig = Func::<P, R>::signature(&*self.module);
+        let id = self.module.declare_function(name, Linkage::Export, &sig)?;
+        // declaring a name again returns the same function, which may be defined already
+        if !self.func_infos.contains_key(&id) {
+            self.undefined_funcs.insert(id);
         }
+        Ok(Func::from_id(id, self.module_id))
     }
 
-    pub fn builder() -> CtxBuilder {
-        CtxBuilder::default()
+    /// Define the body of a function declared with [`Ctx::declare_func`].
+    pub fn define_func<P, R>(&mut self, f: Func<P, R>, body: impl FnOnce(P::Values) -> R::Results)
+    where
+        P: Params,
+        R: Results,
+    {
+        self.try_define_func(f, body)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_define_func<P, R>(
+        &mut self,
+        f: Func<P, R>,
+        body: impl FnOnce(P::Values) -> R::Results,
+    ) -> Result<(), LegoError>
+    where
+        P: Params,
+        R: Results,
+    {
+        self.check_owned(&f);
+        f.try_define(self, body)
+    }
+
+    /// Import the host function registered as `name`, see [`CtxBuilder::register_host_functions`].
+    ///
+    /// Calls to the function are emitted by name, and show up as such in [`FuncInfo`].
+    pub fn import_func<P, R>(&mut self, name: &str) -> Func<P, R>
+    where
+        P: Params,
+        R: Results,
+    {
+        self.try_import_func(name).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Import the host function registered as `name`, failing if it wasn't registered, or if its
+    /// signature doesn't match `P` and `R`.
+    pub fn try_import_func<P, R>(&mut self, name: &str) -> Result<Func<P, R>, LegoError>
+    where
+        P: Params,
+        R: Results,
+    {
+        let host_fn = self
+            .host_fns
+            .iter()
+            .find(|f| f.name == name)
+            .ok_or_else(|| LegoError::UnknownHostFn(name.to_string()))?;
+
+        let mut expected = self.module.make_signature();
+        (host_fn.signature)(&mut expected);
+        let found = Func::<P, R>::signature(&*self.module);
+        if expected != found {
+            return Err(LegoError::HostFnSignature {
+                name: name.to_string(),
+                expected: Box::new(expected),
+                found: Box::new(found),
+            });
+        }
+
+        let id = self
+            .module
+            .declare_function(name, Linkage::Import, &found)?;
+        Ok(Func::from_id(id, self.module_id))
+    }
+
+    /// Look up a function declared with [`Ctx::declare_func`] by name. Returns `None` if there
+    /// is no such function, or if its signature doesn't match `P` and `R`.
+    pub fn get_func<P, R>(&self, name: &str) -> Option<Func<P, R>>
+    where
+        P: Params,
+        R: Results,
+    {
+        let Some(FuncOrDataId::Func(id)) = self.module.get_name(name) else {
+            return None;
+        };
+
+        let decl = self.module.declarations().get_function_decl(id);
+        (decl.signature == Func::<P, R>::signature(&*self.module))
+            .then(|| Func::from_id(id, self.module_id))
+    }
+
+    /// Inspect the code generated for `f`. Returns `None` if `f` was declared but not defined
+    /// yet, or imported from the host.
+    pub fn inspect<P, R>(&self, f: Func<P, R>) -> Option<&FuncInfo> {
+        self.check_owned(&f);
+        self.func_infos.get(&f.id())
+    }
+
+    fn check_defined(&self) -> Result<(), LegoError> {
+        if self.undefined_funcs.is_empty() {
+            return Ok(());
+        }
+
+        let mut names = self
+            .undefined_funcs
+            .iter()
+            .map(|id| {
+                self.module
+                    .declarations()
+                    .get_function_decl(*id)
+                    .linkage_name(*id)
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        Err(LegoError::UndefinedFuncs(names))
+    }
+
+    fn check_owned<P, R>(&self, f: &Func<P, R>) {
+        assert_eq!(
+            f.module_id(),
+            self.module_id,
+            "function belongs to another context, or was freed by a reset"
+        );
     }
 
     pub fn ctx(&self) -> &cranelift::prelude::codegen::Context {
         &self.ctx
     }
 }
+
+impl Ctx<ObjectModule> {
+    /// Emit the object file containing all the functions defined in this context.
+    ///
+    /// Only functions declared with [`Ctx::declare_func`] are exported, under their name. Fails
+    /// if some of them aren't defined.
+    pub fn emit(mut self) -> Result<Vec<u8>, LegoError> {
+        self.check_defined()?;
+        // swap the module for an empty one, to be freed by the context
+        let module = object_module(self.isa.clone(), "")?;
+        let module = std::mem::replace(&mut *self.module, module);
+        module.finish().emit().map_err(LegoError::Object)
+    }
+}
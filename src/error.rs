@@ -0,0 +1,121 @@
+use std::fmt;
+
+use cranelift_codegen::ir::Signature;
+use cranelift_codegen::settings::SetError;
+use cranelift_codegen::verifier::VerifierErrors;
+use cranelift_codegen::CodegenError;
+use cranelift_module::ModuleError;
+use cranelift_object::object;
+
+/// Errors surfaced while building a [`Ctx`](crate::ctx::Ctx) or compiling a function.
+#[derive(Debug)]
+pub enum LegoError {
+    /// Cranelift doesn't support the host machine.
+    UnsupportedHost(String),
+    /// A setting passed to the [`CtxBuilder`](crate::ctx::CtxBuilder) was rejected.
+    InvalidSetting {
+        name: String,
+        value: String,
+        error: SetError,
+    },
+    /// The generated IR is invalid. `ir` is the function annotated with the errors.
+    Verifier { errors: VerifierErrors, ir: String },
+    /// Cranelift failed to generate code. `ir` is the function being compiled, if any.
+    Codegen {
+        error: Box<CodegenError>,
+        ir: Option<String>,
+    },
+    /// The module failed to declare, define or finalize a function.
+    Module(Box<ModuleError>),
+    /// The object file couldn't be written.
+    Object(object::write::Error),
+    /// No host function was registered with this name.
+    UnknownHostFn(String),
+    /// A host function was imported with a signature different from the one it was registered
+    /// with.
+    HostFnSignature {
+        name: String,
+        expected: Box<Signature>,
+        found: Box<Signature>,
+    },
+    /// Functions declared with [`Ctx::declare_func`](crate::ctx::Ctx::declare_func) were never
+    /// defined, with their names.
+    UndefinedFuncs(Vec<String>),
+    /// A host closure was called by a function emitted to an object file. Closures only exist
+    /// in the current process, use a host function imported by name instead.
+    HostClosureInObject,
+}
+
+impl LegoError {
+    /// The IR of the function that failed to compile, if any.
+    pub fn ir(&self) -> Option<&str> {
+        match self {
+            LegoError::Verifier { ir, .. } => Some(ir),
+            LegoError::Codegen { ir, .. } => ir.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LegoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LegoError::UnsupportedHost(msg) => write!(f, "host machine is not supported: {msg}"),
+            LegoError::InvalidSetting { name, value, error } => {
+                write!(f, "invalid setting `{name}={value}`: {error}")
+            }
+            LegoError::Verifier { errors, ir } => {
+                write!(f, "verifier error: {errors}\n{ir}")
+            }
+            LegoError::Codegen { error, ir } => {
+                write!(f, "codegen error: {error}")?;
+                if let Some(ir) = ir {
+                    write!(f, "\n{ir}")?;
+                }
+                Ok(())
+            }
+            LegoError::Module(error) => write!(f, "module error: {error}"),
+            LegoError::Object(error) => write!(f, "object error: {error}"),
+            LegoError::UnknownHostFn(name) => write!(f, "no host function named `{name}`"),
+            LegoError::HostFnSignature {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "host function `{name}` has signature `{expected}`, but was imported as `{found}`"
+            ),
+            LegoError::UndefinedFuncs(names) => write!(
+                f,
+                "functions are declared but not defined: {}",
+                names.join(", ")
+            ),
+            LegoError::HostClosureInObject => {
+                write!(f, "host closures can't be called from an object file")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LegoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LegoError::InvalidSetting { error, .. } => Some(error),
+            LegoError::Verifier { errors, .. } => Some(errors),
+            LegoError::Codegen { error, .. } => Some(&**error),
+            LegoError::Module(error) => Some(&**error),
+            LegoError::Object(error) => Some(error),
+            LegoError::UnsupportedHost(_)
+            | LegoError::UnknownHostFn(_)
+            | LegoError::HostFnSignature { .. }
+            | LegoError::UndefinedFuncs(_)
+            | LegoError::HostClosureInObject => None,
+        }
+    }
+}
+
+impl From<ModuleError> for LegoError {
+    fn from(error: ModuleError) -> Self {
+        LegoError::Module(Box::new(error))
+    }
+}
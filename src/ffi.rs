@@ -1,13 +1,46 @@
 use core::fmt;
+use std::mem::MaybeUninit;
+use std::{panic, thread};
 
 use super::func::CompiledFunc;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use crate::trap::{call_checked, Trap};
+use crate::{for_all_floats, for_all_integers, for_all_primitives, for_all_tuples};
 
 pub trait Function {
     type FFIFn: ToFFIFunctionParams;
     type Params;
     type Result;
 
-    fn call(&self, params: Self::Params) -> Self::Result;
+    /// Call the function, resuming the panic of a host closure it called, if any.
+    fn call(&self, params: Self::Params) -> Self::Result {
+        self.try_call(params)
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Call the function, returning the payload of the panic of a host closure it called, if
+    /// any. The function returned as soon as the closure panicked.
+    fn try_call(&self, params: Self::Params) -> thread::Result<Self::Result>;
+
+    /// Call the function, returning the trap it hit instead of killing the process with
+    /// `SIGFPE` or `SIGILL`. The first checked call installs signal handlers for the process,
+    /// which forward the signals that aren't traps of the generated code to the handlers
+    /// installed before.
+    ///
+    /// Only available on linux, on x86_64 and aarch64: elsewhere, traps can't be caught and
+    /// abort the process.
+    ///
+    /// Only traps of the generated code called directly are caught: the function is called
+    /// without any rust frame in between, that the trap would skip. Traps in generated code
+    /// called by a host closure abort the process, unless that call is checked as well.
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn call_checked(&self, params: Self::Params) -> Result<Self::Result, Trap>;
 }
 
 #[derive(Debug)]
@@ -15,57 +48,186 @@ pub struct Bottom;
 
 pub trait ToFFIFunctionParams: fmt::Debug {
     unsafe fn call<R>(self, f: *const u8) -> R;
+
+    /// Write the arguments to `args`, in order.
+    fn push_args(self, args: &mut RawArgs);
 }
 
-pub trait ToFFIParams: fmt::Debug {
+/// The arguments of a call, as passed in the registers and stack slots of the C calling
+/// convention: integers and pointers widened to 64 bits, and floats as their bits.
+#[doc(hidden)]
+#[repr(C)]
+#[derive(Default)]
+pub struct RawArgs {
+    ints: [u64; 16],
+    floats: [u64; 8],
+    n_ints: usize,
+    n_floats: usize,
+}
+
+impl RawArgs {
+    fn push_int(&mut self, val: u64) {
+        self.ints[self.n_ints] = val;
+        self.n_ints += 1;
+    }
+
+    fn push_float(&mut self, bits: u64) {
+        // at most 7 params can be floats, they always fit in registers
+        self.floats[self.n_floats] = bits;
+        self.n_floats += 1;
+    }
+}
+
+/// The integer and float return registers of a call.
+#[doc(hidden)]
+#[repr(C)]
+#[derive(Default)]
+pub struct RawRet {
+    int: u64,
+    float: u64,
+}
+
+fn raw_args(params: impl ToFFIFunctionParams) -> RawArgs {
+    let mut args = RawArgs::default();
+    params.push_args(&mut args);
+    args
+}
+
+pub trait ToFFIParams {
     type Out<T: fmt::Debug>: std::fmt::Debug;
 
     fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T>;
 }
 
-trait Primitive: fmt::Debug {}
+/// Types passed as a single argument to the compiled function.
+trait Primitive: fmt::Debug {
+    fn push_arg(self, args: &mut RawArgs);
+
+    /// The value returned in `ret`.
+    fn from_ret(ret: &RawRet) -> Self;
+}
 
-impl Primitive for u64 {}
-impl Primitive for i32 {}
-impl Primitive for usize {}
-impl<T: fmt::Debug> Primitive for &[T] {}
+impl<T> Primitive for *const T {
+    fn push_arg(self, args: &mut RawArgs) {
+        args.push_int(self as usize as u64);
+    }
 
-impl<T: Primitive> ToFFIFunctionParams for Param<Bottom, T> {
-    unsafe fn call<R>(self, f: *const u8) -> R {
-        let Param(_, a) = self;
-        let f = std::mem::transmute::<*const u8, extern "C" fn(T) -> R>(f);
-        f(a)
+    fn from_ret(ret: &RawRet) -> Self {
+        ret.int as usize as *const T
     }
 }
 
-impl<T: Primitive + fmt::Debug, U: Primitive + fmt::Debug> ToFFIFunctionParams for Param<Param<Bottom, T>, U> {
-    unsafe fn call<R>(self, f: *const u8) -> R {
-        let Param(Param(_, a), b) = self;
-        let f = std::mem::transmute::<*const u8, extern "C" fn(U, T) -> R>(f);
-        f(b, a)
+impl<T> Primitive for *mut T {
+    fn push_arg(self, args: &mut RawArgs) {
+        args.push_int(self as usize as u64);
     }
-}
 
-impl<T: Primitive, U: Primitive, V: Primitive> ToFFIFunctionParams
-    for Param<Param<Param<Bottom, T>, U>, V>
-{
-    unsafe fn call<R>(self, f: *const u8) -> R {
-        let Param(Param(Param(_, a), b), c) = self;
-        let f = std::mem::transmute::<*const u8, extern "C" fn(V, U, T) -> R>(f);
-        f(c, b, a)
+    fn from_ret(ret: &RawRet) -> Self {
+        ret.int as usize as *mut T
     }
 }
 
-impl<T: Primitive + fmt::Debug, U: Primitive + fmt::Debug, V: Primitive + fmt::Debug, W: Primitive + fmt::Debug> ToFFIFunctionParams
-    for Param<Param<Param<Param<Bottom, T>, U>, V>, W>
-{
-    unsafe fn call<R>(self, f: *const u8) -> R {
-        let Param(Param(Param(Param(_, a), b), c), d) = self;
-        let f = std::mem::transmute::<*const u8, extern "C" fn(W, V, U, T) -> R>(f);
-        f(d, c, b, a)
+impl Primitive for bool {
+    fn push_arg(self, args: &mut RawArgs) {
+        args.push_int(self as u64);
     }
+
+    fn from_ret(ret: &RawRet) -> Self {
+        ret.int as u8 != 0
+    }
+}
+
+// signed integers are sign extended, in case the callee expects it
+macro_rules! impl_ffi_primitive_int {
+    ($ty:ident) => {
+        impl Primitive for $ty {
+            fn push_arg(self, args: &mut RawArgs) {
+                args.push_int(self as i64 as u64);
+            }
+
+            fn from_ret(ret: &RawRet) -> Self {
+                ret.int as $ty
+            }
+        }
+    };
+}
+
+for_all_integers!(impl_ffi_primitive_int);
+
+macro_rules! impl_ffi_primitive_float {
+    ($ty:ident) => {
+        impl Primitive for $ty {
+            fn push_arg(self, args: &mut RawArgs) {
+                args.push_float(self.to_bits() as u64);
+            }
+
+            fn from_ret(ret: &RawRet) -> Self {
+                $ty::from_bits(ret.float as _)
+            }
+        }
+    };
+}
+
+for_all_floats!(impl_ffi_primitive_float);
+
+/// `ffi_nested!(A, B)` is `Param<Param<Bottom, B>, A>`: the outermost param is the first
+/// argument.
+macro_rules! ffi_nested {
+    () => {
+        Bottom
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        Param<ffi_nested!($($tail),*), $head>
+    };
 }
 
+/// The pattern destructuring `ffi_nested!(A, B)`, binding each argument to its type's name.
+macro_rules! ffi_pat {
+    () => {
+        Bottom
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        Param(ffi_pat!($($tail),*), $head)
+    };
+}
+
+macro_rules! impl_to_ffi_function_params {
+    ($($ty:ident $(,)?)*) => {
+        impl<$($ty: Primitive,)*> ToFFIFunctionParams for ffi_nested!($($ty),*) {
+            #[allow(non_snake_case)]
+            unsafe fn call<R>(self, f: *const u8) -> R {
+                let ffi_pat!($($ty),*) = self;
+                let f = std::mem::transmute::<*const u8, extern "C" fn($($ty),*) -> R>(f);
+                f($($ty),*)
+            }
+
+            #[allow(non_snake_case)]
+            fn push_args(self, args: &mut RawArgs) {
+                let ffi_pat!($($ty),*) = self;
+                $($ty.push_arg(args);)*
+            }
+        }
+    };
+}
+
+// slices are passed as two arguments, so a 7-tuple can take up to 14 arguments, plus the pointer to
+// tuple results.
+impl_to_ffi_function_params!(A);
+impl_to_ffi_function_params!(A, B);
+impl_to_ffi_function_params!(A, B, C);
+impl_to_ffi_function_params!(A, B, C, D);
+impl_to_ffi_function_params!(A, B, C, D, E);
+impl_to_ffi_function_params!(A, B, C, D, E, F);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I, J);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I, J, K);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I, J, K, L);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I, J, K, L, M);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I, J, K, L, M, N);
+impl_to_ffi_function_params!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O);
+
 #[derive(Debug)]
 pub struct Param<T, U>(T, U);
 
@@ -73,31 +235,54 @@ pub trait ToTuple {
     type Output;
 }
 
-impl ToFFIParams for u64 {
-    type Out<T: fmt::Debug> = Param<T, u64>;
+macro_rules! impl_ffi_primitive {
+    ($ty:ident) => {
+        impl ToFFIParams for $ty {
+            type Out<T: fmt::Debug> = Param<T, $ty>;
+
+            fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
+                Param(t, self)
+            }
+        }
+    };
+}
+
+for_all_primitives!(impl_ffi_primitive);
 
-    fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
+impl<T> ToFFIParams for *const T {
+    type Out<U: fmt::Debug> = Param<U, *const T>;
+
+    fn to_ffi_params<U: fmt::Debug>(self, t: U) -> Self::Out<U> {
         Param(t, self)
     }
 }
 
-impl ToFFIParams for i32 {
-    type Out<T: fmt::Debug> = Param<T, i32>;
+impl<T> ToFFIParams for *mut T {
+    type Out<U: fmt::Debug> = Param<U, *mut T>;
 
-    fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
+    fn to_ffi_params<U: fmt::Debug>(self, t: U) -> Self::Out<U> {
         Param(t, self)
     }
 }
 
-impl ToFFIParams for usize {
-    type Out<T: fmt::Debug> = Param<T, usize>;
+// references are passed as pointers, so the pointee doesn't need to be `Debug`.
+impl<T> ToFFIParams for &T {
+    type Out<U: fmt::Debug> = Param<U, *const T>;
 
-    fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
-        Param(t, self)
+    fn to_ffi_params<U: fmt::Debug>(self, t: U) -> Self::Out<U> {
+        Param(t, self as *const T)
     }
 }
 
-impl<T: fmt::Debug> ToFFIParams for &[T] {
+impl<T> ToFFIParams for &mut T {
+    type Out<U: fmt::Debug> = Param<U, *mut T>;
+
+    fn to_ffi_params<U: fmt::Debug>(self, t: U) -> Self::Out<U> {
+        Param(t, self as *mut T)
+    }
+}
+
+impl<T> ToFFIParams for &[T] {
     type Out<U: fmt::Debug> = <usize as ToFFIParams>::Out<<usize as ToFFIParams>::Out<U>>;
 
     fn to_ffi_params<U: fmt::Debug>(self, t: U) -> Self::Out<U> {
@@ -105,45 +290,293 @@ impl<T: fmt::Debug> ToFFIParams for &[T] {
     }
 }
 
-impl<A, B> ToFFIParams for (A, B)
-where
-    A: ToFFIParams,
-    B: ToFFIParams,
-{
-    type Out<T: fmt::Debug> = A::Out<B::Out<T>>;
+impl<T> ToFFIParams for &mut [T] {
+    type Out<U: fmt::Debug> = <usize as ToFFIParams>::Out<<usize as ToFFIParams>::Out<U>>;
 
-    fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
-        self.0.to_ffi_params(self.1.to_ffi_params(t))
+    fn to_ffi_params<U: fmt::Debug>(self, t: U) -> Self::Out<U> {
+        Param(Param(t, self.as_mut_ptr() as usize), self.len())
     }
 }
 
-impl<A, B, C> ToFFIParams for (A, B, C)
-where
-    A: ToFFIParams,
-    B: ToFFIParams,
-    C: ToFFIParams,
-{
-    type Out<T: fmt::Debug> = A::Out<B::Out<C::Out<T>>>;
+/// `ffi_out!(T; A, B)` is `A::Out<B::Out<T>>`
+macro_rules! ffi_out {
+    ($t:ty;) => {
+        $t
+    };
+    ($t:ty; $head:ident $(, $tail:ident)*) => {
+        <$head as ToFFIParams>::Out<ffi_out!($t; $($tail),*)>
+    };
+}
+
+/// `ffi_chain!(t; a, b)` is `a.to_ffi_params(b.to_ffi_params(t))`
+macro_rules! ffi_chain {
+    ($t:ident;) => {
+        $t
+    };
+    ($t:ident; $head:ident $(, $tail:ident)*) => {
+        $head.to_ffi_params(ffi_chain!($t; $($tail),*))
+    };
+}
+
+macro_rules! impl_ffi_params_tuples {
+    ($($ty:ident $(,)?)*) => {
+        impl<$($ty,)*> ToFFIParams for ($($ty,)*)
+        where
+            $($ty: ToFFIParams,)*
+        {
+            type Out<T: fmt::Debug> = ffi_out!(T; $($ty),*);
+
+            #[allow(non_snake_case)]
+            fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
+                let ($($ty,)*) = self;
+                ffi_chain!(t; $($ty),*)
+            }
+        }
+    };
+}
 
-    fn to_ffi_params<T: fmt::Debug>(self, t: T) -> Self::Out<T> {
-        self.0
-            .to_ffi_params(self.1.to_ffi_params(self.2.to_ffi_params(t)))
+for_all_tuples!(impl_ffi_params_tuples);
+
+/// Results of a compiled function called with the params `P`.
+///
+/// If a host closure called by the function panicked, the function returns early with
+/// meaningless values, e.g. a null reference. The call returns them as `Raw`, which is only
+/// converted to `Self` once it is known that there was no panic.
+pub trait ToFFIResults<P>: Sized {
+    type Raw;
+
+    /// # Safety
+    /// `f` must point to a function taking `P` and returning `Self`.
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw;
+
+    /// Like [`ToFFIResults::call`], with the function called by `call`, given the arguments
+    /// and returning its return registers.
+    ///
+    /// # Safety
+    /// `call` must call a function taking `P` and returning `Self`.
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR>;
+
+    /// # Safety
+    /// `raw` must have been returned by a call during which no host closure panicked.
+    unsafe fn from_raw(raw: Self::Raw) -> Self;
+}
+
+macro_rules! impl_ffi_results_scalar {
+    ($ty:ident) => {
+        impl<P: ToFFIFunctionParams> ToFFIResults<P> for $ty {
+            type Raw = $ty;
+
+            unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+                params.call(f)
+            }
+
+            unsafe fn call_raw<ERR>(
+                params: P,
+                call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+            ) -> Result<Self::Raw, ERR> {
+                call(&raw_args(params)).map(|ret| $ty::from_ret(&ret))
+            }
+
+            unsafe fn from_raw(raw: Self::Raw) -> Self {
+                raw
+            }
+        }
+    };
+}
+
+for_all_primitives!(impl_ffi_results_scalar);
+
+impl<P: ToFFIFunctionParams> ToFFIResults<P> for () {
+    type Raw = ();
+
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+        params.call(f)
+    }
+
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR> {
+        call(&raw_args(params)).map(|_| ())
+    }
+
+    unsafe fn from_raw(raw: Self::Raw) -> Self {
+        raw
     }
 }
 
+impl<P: ToFFIFunctionParams> ToFFIResults<P> for bool {
+    type Raw = u8;
+
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+        params.call(f)
+    }
+
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR> {
+        call(&raw_args(params)).map(|ret| u8::from_ret(&ret))
+    }
+
+    unsafe fn from_raw(raw: Self::Raw) -> Self {
+        raw != 0
+    }
+}
+
+impl<P: ToFFIFunctionParams, T> ToFFIResults<P> for *const T {
+    type Raw = *const T;
+
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+        params.call(f)
+    }
+
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR> {
+        call(&raw_args(params)).map(|ret| <*const T>::from_ret(&ret))
+    }
+
+    unsafe fn from_raw(raw: Self::Raw) -> Self {
+        raw
+    }
+}
+
+impl<P: ToFFIFunctionParams, T> ToFFIResults<P> for *mut T {
+    type Raw = *mut T;
+
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+        params.call(f)
+    }
+
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR> {
+        call(&raw_args(params)).map(|ret| <*mut T>::from_ret(&ret))
+    }
+
+    unsafe fn from_raw(raw: Self::Raw) -> Self {
+        raw
+    }
+}
+
+impl<P: ToFFIFunctionParams, T> ToFFIResults<P> for &T {
+    type Raw = *const T;
+
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+        params.call(f)
+    }
+
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR> {
+        call(&raw_args(params)).map(|ret| <*const T>::from_ret(&ret))
+    }
+
+    unsafe fn from_raw(raw: Self::Raw) -> Self {
+        &*raw
+    }
+}
+
+impl<P: ToFFIFunctionParams, T> ToFFIResults<P> for &mut T {
+    type Raw = *mut T;
+
+    unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+        params.call(f)
+    }
+
+    unsafe fn call_raw<ERR>(
+        params: P,
+        call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+    ) -> Result<Self::Raw, ERR> {
+        call(&raw_args(params)).map(|ret| <*mut T>::from_ret(&ret))
+    }
+
+    unsafe fn from_raw(raw: Self::Raw) -> Self {
+        &mut *raw
+    }
+}
+
+// tuples are written to a buffer passed as first argument, each value in its own slot. See
+// `Results`.
+macro_rules! impl_ffi_results_tuples {
+    ($($ty:ident $(,)?)*) => {
+        impl<P, $($ty: Primitive,)*> ToFFIResults<P> for ($($ty,)*)
+        where
+            Param<P, *mut u64>: ToFFIFunctionParams,
+        {
+            type Raw = [MaybeUninit<u64>; 7];
+
+            unsafe fn call(params: P, f: *const u8) -> Self::Raw {
+                let mut out = [MaybeUninit::<u64>::uninit(); 7];
+                Param(params, out.as_mut_ptr() as *mut u64).call::<()>(f);
+                out
+            }
+
+            unsafe fn call_raw<ERR>(
+                params: P,
+                call: impl FnOnce(&RawArgs) -> Result<RawRet, ERR>,
+            ) -> Result<Self::Raw, ERR> {
+                let mut out = [MaybeUninit::<u64>::uninit(); 7];
+                call(&raw_args(Param(params, out.as_mut_ptr() as *mut u64)))?;
+                Ok(out)
+            }
+
+            #[allow(non_snake_case, unused_assignments)]
+            unsafe fn from_raw(raw: Self::Raw) -> Self {
+                let mut slot = raw.as_ptr();
+                $(
+                    let $ty = slot.cast::<$ty>().read();
+                    slot = slot.add(1);
+                )*
+                ($($ty,)*)
+            }
+        }
+    };
+}
+
+for_all_tuples!(impl_ffi_results_tuples);
+
 impl<A, R> Function for CompiledFunc<'_, A, R>
 where
     A: ToFFIParams,
     A::Out<Bottom>: ToFFIFunctionParams,
+    R: ToFFIResults<A::Out<Bottom>>,
 {
     type Params = A;
     type FFIFn = A::Out<Bottom>;
     type Result = R;
 
-    fn call(&self, params: Self::Params) -> Self::Result {
+    fn try_call(&self, params: Self::Params) -> thread::Result<Self::Result> {
         let params = params.to_ffi_params(Bottom);
         // safety: CompiledFunction guarantees the provenance of the function pointer, and we
         // correct type is asserted at compile time.
-        unsafe { params.call(self.ptr) }
+        let raw = unsafe { R::call(params, self.ptr) };
+        match self.panic_slot.take() {
+            Some(payload) => Err(payload),
+            // safety: no host closure panicked, the function returned its actual results
+            None => Ok(unsafe { R::from_raw(raw) }),
+        }
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn call_checked(&self, params: Self::Params) -> Result<Self::Result, Trap> {
+        let params = params.to_ffi_params(Bottom);
+        // safety: as for `try_call`
+        let raw =
+            unsafe { R::call_raw(params, |args| call_checked(self.trap_sites, self.ptr, args))? };
+        match self.panic_slot.take() {
+            Some(payload) => panic::resume_unwind(payload),
+            None => Ok(unsafe { R::from_raw(raw) }),
+        }
     }
 }
@@ -1,17 +1,28 @@
-use std::cell::RefCell;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
-
-use cranelift::prelude::{Block, InstBuilder, Value};
+use std::panic::{self, AssertUnwindSafe, RefUnwindSafe};
+
+use cranelift::prelude::{
+    types, AbiParam, Block, InstBuilder, MemFlags, Signature, StackSlotData, StackSlotKind,
+    TrapCode, Value,
+};
+use cranelift_codegen::ir::{ExternalName, Function, Inst};
+use cranelift_codegen::print_errors::pretty_verifier_error;
+use cranelift_codegen::CodegenError;
 use cranelift_frontend::{FunctionBuilder, Variable};
-use cranelift_jit::JITModule;
-use cranelift_module::{FuncId, Module};
+use cranelift_module::{FuncId, Module, ModuleError};
 
 // use crate::prelude::ControlFlow;
 use crate::abi_params::ToAbiParams;
-use crate::ctx::Ctx;
+use crate::backend::Backend;
+use crate::control_flow::LoopFrame;
+use crate::ctx::{Ctx, FuncInfo};
+use crate::error::LegoError;
 use crate::primitive::Primitive;
 use crate::proxy::{Ptr, PtrMut};
+use crate::trap::in_host_closure;
 use crate::val::{AsVal, Val};
 use crate::var::Var;
 use crate::{for_all_primitives, for_all_tuples, maybe_paren};
@@ -23,19 +34,72 @@ thread_local! {
 #[derive(Copy, Clone)]
 pub struct Func<P, R> {
     id: FuncId,
+    /// the module the function was declared in
+    module_id: u64,
     _pth: PhantomData<fn(P) -> R>,
 }
 
+/// A function ready to be called, borrowing the [`Ctx`] that owns its code.
 pub struct CompiledFunc<'a, P, R> {
     pub(crate) ptr: *const u8,
+    /// where host closures called by the function report panics
+    pub(crate) panic_slot: &'a PanicSlot,
+    /// where the code of the context can trap, see [`Function::call_checked`](crate::ffi::Function::call_checked)
+    #[cfg_attr(
+        not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) trap_sites: &'a [(usize, TrapCode)],
     pub(crate) _pth: PhantomData<&'a fn(P) -> R>,
 }
 
+/// Where a host closure that panicked leaves the panic payload.
+///
+/// Host closures are called from `extern "C"` trampolines, that can't unwind. Instead, the
+/// trampoline catches the panic and records it here, and the generated code checks the slot
+/// after each call, returning early if it is set. [`CompiledFunc`] then takes the payload.
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct PanicSlot {
+    /// read by the generated code, must remain the first field
+    panicked: Cell<bool>,
+    payload: Cell<Option<Box<dyn Any + Send>>>,
+}
+
+// the payload is taken before the panic is resumed, the slot is never left half updated
+impl RefUnwindSafe for PanicSlot {}
+
+impl PanicSlot {
+    fn set(&self, payload: Box<dyn Any + Send>) {
+        self.payload.set(Some(payload));
+        self.panicked.set(true);
+    }
+
+    pub(crate) fn take(&self) -> Option<Box<dyn Any + Send>> {
+        self.panicked.set(false);
+        self.payload.take()
+    }
+}
+
 pub struct FnCtx<'a> {
     pub(crate) builder: FunctionBuilder<'a>,
-    pub(crate) module: &'a mut JITModule,
+    pub(crate) module: &'a mut dyn Module,
     pub(crate) var_id: u32,
     pub(crate) current_block: Block,
+    /// loops we are currently emitting the body of, innermost last
+    pub(crate) loops: Vec<LoopFrame>,
+    pub(crate) module_id: u64,
+    /// where the results are written, if they are returned through a pointer. See [`Results`].
+    pub(crate) ret_ptr: Option<Value>,
+    /// environments of the host closures called by the function
+    pub(crate) host_envs: &'a mut Vec<Box<dyn Any>>,
+    /// where host closures report panics, if the code runs in the current process
+    pub(crate) panic_slot: Option<*const PanicSlot>,
+    /// an error hit while emitting the body, returned once it is done
+    pub(crate) error: Option<LegoError>,
 }
 
 impl<'a> FnCtx<'a> {
@@ -53,13 +117,19 @@ impl<'a> FnCtx<'a> {
         out
     }
 
+    pub(crate) fn innermost_loop(&self) -> &LoopFrame {
+        self.loops
+            .last()
+            .expect("break or continue outside of a staged loop")
+    }
+
     #[doc(hidden)]
     pub fn builder(&mut self) -> &mut FunctionBuilder<'a> {
         &mut self.builder
     }
 
     #[doc(hidden)]
-    pub fn module(&mut self) -> &mut JITModule {
+    pub fn module(&mut self) -> &mut dyn Module {
         self.module
     }
 }
@@ -93,6 +163,10 @@ impl<P, R> Func<P, R> {
     pub fn id(&self) -> FuncId {
         self.id
     }
+
+    pub(crate) fn module_id(&self) -> u64 {
+        self.module_id
+    }
 }
 
 impl<P, R> Func<P, R>
@@ -100,12 +174,49 @@ where
     P: Params,
     R: Results,
 {
-    pub(crate) fn new<B>(ctx: &mut Ctx, body: B) -> Self
+    pub(crate) fn new<M: Backend, B>(ctx: &mut Ctx<M>, body: B) -> Self
+    where
+        B: FnOnce(P::Values) -> R::Results,
+    {
+        Self::try_new(ctx, body).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub(crate) fn try_new<M: Backend, B>(ctx: &mut Ctx<M>, body: B) -> Result<Self, LegoError>
+    where
+        B: FnOnce(P::Values) -> R::Results,
+    {
+        let sig = Self::signature(&*ctx.module);
+        let id = ctx.module.declare_anonymous_function(&sig)?;
+        let func = Self::from_id(id, ctx.module_id);
+        func.try_define(ctx, body)?;
+        Ok(func)
+    }
+
+    pub(crate) fn from_id(id: FuncId, module_id: u64) -> Self {
+        Self {
+            id,
+            module_id,
+            _pth: PhantomData,
+        }
+    }
+
+    pub(crate) fn signature(module: &impl Module) -> Signature {
+        let mut sig = module.make_signature();
+        P::to_abi_params(&mut sig.params);
+        R::to_abi_returns(&mut sig);
+        sig
+    }
+
+    /// Emit the body of a declared function.
+    pub(crate) fn try_define<M: Backend, B>(
+        &self,
+        ctx: &mut Ctx<M>,
+        body: B,
+    ) -> Result<(), LegoError>
     where
         B: FnOnce(P::Values) -> R::Results,
     {
-        P::to_abi_params(&mut ctx.ctx.func.signature.params);
-        R::to_abi_params(&mut ctx.ctx.func.signature.returns);
+        ctx.ctx.func.signature = Self::signature(&*ctx.module);
         let mut builder = FunctionBuilder::new(&mut ctx.ctx.func, &mut ctx.fn_builder_ctx);
 
         let block0 = builder.create_block();
@@ -114,11 +225,20 @@ where
         builder.seal_block(block0);
 
         let mut fn_ctx = FnCtx {
-            module: &mut ctx.module,
+            module: &mut *ctx.module,
             builder,
             var_id: 0,
             current_block: block0,
+            loops: Vec::new(),
+            module_id: ctx.module_id,
+            ret_ptr: None,
+            host_envs: &mut ctx.host_envs,
+            panic_slot: M::IN_PROCESS.then_some(&*ctx.panic_slot as *const PanicSlot),
+            error: None,
         };
+        if R::RET_PTR {
+            fn_ctx.ret_ptr = Some(fn_ctx.builder.block_params(block0)[0]);
+        }
 
         let params = P::initialize(&mut fn_ctx);
 
@@ -126,31 +246,50 @@ where
 
         ret.return_(&mut fn_ctx);
 
+        let error = fn_ctx.error.take();
         fn_ctx.builder.finalize();
+        if let Some(e) = error {
+            ctx.module.clear_context(&mut ctx.ctx);
+            return Err(e);
+        }
 
-        let func_id = ctx
-            .module
-            .declare_anonymous_function(&ctx.ctx.func.signature)
-            .unwrap();
-
-        ctx.module.define_function(func_id, &mut ctx.ctx).unwrap();
-        println!(
-            "{}",
-            ctx.ctx
-                .compiled_code()
-                .as_ref()
-                .unwrap()
+        // clearing the context resets the disasm flag, it must be set for each function
+        ctx.ctx.set_disasm(ctx.disasm);
+        let ir = ctx.disasm.then(|| {
+            ext_funcs_legend(&ctx.ctx.func, &*ctx.module) + &ctx.ctx.func.display().to_string()
+        });
+        if let Err(e) = ctx.module.define_function(self.id, &mut ctx.ctx) {
+            let e = define_error(&ctx.ctx.func, e);
+            // leave the context ready for the next function
+            ctx.module.clear_context(&mut ctx.ctx);
+            return Err(e);
+        }
+        let compiled = ctx.ctx.compiled_code().unwrap();
+        let info = FuncInfo {
+            ir,
+            disasm: compiled
                 .vcode
                 .as_ref()
-                .unwrap()
-        );
+                .map(|vcode| ext_funcs_legend(&ctx.ctx.func, &*ctx.module) + vcode),
+            code_size: compiled.code_buffer().len(),
+        };
+        let traps = compiled
+            .buffer
+            .traps()
+            .iter()
+            .map(|trap| (trap.offset, trap.code))
+            .collect();
+        ctx.pending_traps.push((self.id, traps));
+        ctx.func_infos.insert(self.id, info);
         ctx.module.clear_context(&mut ctx.ctx);
-        ctx.module.finalize_definitions().unwrap();
 
-        Self {
-            _pth: PhantomData,
-            id: func_id,
+        // functions can only be finalized once all the functions they call are defined
+        ctx.undefined_funcs.remove(&self.id);
+        if ctx.undefined_funcs.is_empty() {
+            ctx.finalize()?;
         }
+
+        Ok(())
     }
 
     pub fn call<T>(&self, params: T) -> R::Results
@@ -158,12 +297,18 @@ where
         T: IntoParams<Input = P>,
     {
         with_ctx(|ctx| {
+            assert_eq!(
+                self.module_id, ctx.module_id,
+                "function belongs to another context, or was freed by a reset"
+            );
             let fn_ref = ctx.module.declare_func_in_func(self.id, ctx.builder.func);
             let mut args = Vec::new();
             params.params(ctx, &mut args);
-            let call = ctx.builder.ins().call(fn_ref, &args);
-            let results = ctx.builder.inst_results(call);
-            R::Results::from_func_ret(results)
+            let ret =
+                R::Results::emit_call(ctx, args, |ctx, args| ctx.builder.ins().call(fn_ref, args));
+            // the callee returned early if a host closure panicked
+            emit_panic_check(ctx);
+            ret
         })
     }
 
@@ -172,6 +317,69 @@ where
     }
 }
 
+/// Return early from the function being built if a host closure panicked during the last call.
+/// The returned values are meaningless, the caller discards them.
+fn emit_panic_check(ctx: &mut FnCtx) {
+    // the address of the slot is only meaningful to code running in the current process
+    let Some(slot_addr) = ctx.panic_slot else {
+        return;
+    };
+    let ptr_ty = ctx.module.target_config().pointer_type();
+    let [unwind_block, cont_block] = ctx.create_blocks();
+    let b = ctx.builder();
+    let slot = b.ins().iconst(ptr_ty, slot_addr as usize as i64);
+    let panicked = b.ins().load(types::I8, MemFlags::trusted(), slot, 0);
+    b.ins().brif(panicked, unwind_block, &[], cont_block, &[]);
+    b.seal_block(unwind_block);
+    b.seal_block(cont_block);
+
+    b.switch_to_block(unwind_block);
+    let returns = b.func.signature.returns.clone();
+    let vals = returns
+        .iter()
+        .map(|ret| match ret.value_type {
+            types::F32 => b.ins().f32const(0.0),
+            types::F64 => b.ins().f64const(0.0),
+            ty => b.ins().iconst(ty, 0),
+        })
+        .collect::<Vec<_>>();
+    b.ins().return_(&vals);
+
+    b.switch_to_block(cont_block);
+}
+
+/// Comments naming the functions called by `func`, which the IR only refers to by id.
+fn ext_funcs_legend(func: &Function, module: &impl Module) -> String {
+    let mut legend = String::new();
+    for (func_ref, data) in func.dfg.ext_funcs.iter() {
+        let ExternalName::User(name_ref) = data.name else {
+            continue;
+        };
+        let name = &func.params.user_named_funcs()[name_ref];
+        let id = FuncId::from_u32(name.index);
+        if let Some(name) = &module.declarations().get_function_decl(id).name {
+            // the IR refers to the function by `func_ref`, the disassembly by `name_ref`
+            legend += &format!("; {func_ref}, {name_ref} = {name}\n");
+        }
+    }
+    legend
+}
+
+/// Attach the IR of `func` to the error returned when defining it.
+fn define_error(func: &Function, e: ModuleError) -> LegoError {
+    match e {
+        ModuleError::Compilation(CodegenError::Verifier(errors)) => {
+            let ir = pretty_verifier_error(func, None, errors.clone());
+            LegoError::Verifier { errors, ir }
+        }
+        ModuleError::Compilation(error) => LegoError::Codegen {
+            error: Box::new(error),
+            ir: Some(func.display().to_string()),
+        },
+        e => e.into(),
+    }
+}
+
 pub trait HostFn {
     type Params;
     type Returns: Results;
@@ -183,8 +391,27 @@ pub trait HostFn {
     ) -> <Self::Returns as Results>::Results;
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct HostFunc<F, P, R>(F, PhantomData<fn(P) -> R>);
+/// A host closure callable from generated code.
+///
+/// The closure is moved into a box by [`IntoHostFn::into_host_fn`], and kept alive by the
+/// [`Ctx`] until its code is freed. It is passed to the host function as a hidden first argument.
+///
+/// A panic in the closure doesn't unwind through the generated code: it is caught, and resumed
+/// or returned by [`Function`](crate::ffi::Function) once the compiled function has returned.
+#[derive(Debug)]
+pub struct HostFunc<F, P, R> {
+    env: *const HostEnv<F>,
+    module_id: u64,
+    _p: PhantomData<fn(P) -> R>,
+}
+
+impl<F, P, R> Clone for HostFunc<F, P, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<F, P, R> Copy for HostFunc<F, P, R> {}
 
 impl<F, P, R> HostFunc<F, P, R>
 where
@@ -199,166 +426,188 @@ where
 }
 
 pub trait IntoHostFn<P, R> {
+    /// Move the closure into the function being built, see [`HostFunc`].
+    ///
+    /// Panics if not called while building a function.
     fn into_host_fn(self) -> HostFunc<Self, P, R>
     where
         Self: Sized;
 }
 
-impl<A, R, F> IntoHostFn<(A,), R> for F
-where
-    F: FnOnce(A) -> R,
-{
-    fn into_host_fn(self) -> HostFunc<Self, (A,), R>
-    where
-        Self: Sized,
-    {
-        HostFunc(self, PhantomData)
-    }
+macro_rules! impl_into_host_fn {
+    ($($ty:ident $(,)?)*) => {
+        impl<$($ty,)* RET, FUN> IntoHostFn<($($ty,)*), RET> for FUN
+        where
+            FUN: Fn($($ty),*) -> RET + 'static,
+        {
+            fn into_host_fn(self) -> HostFunc<Self, ($($ty,)*), RET>
+            where
+                Self: Sized,
+            {
+                with_ctx(|ctx| HostFunc {
+                    env: host_env(ctx, self),
+                    module_id: ctx.module_id,
+                    _p: PhantomData,
+                })
+            }
+        }
+    };
 }
 
-impl<A, B, R, F> IntoHostFn<(A, B), R> for F
-where
-    F: FnOnce(A, B) -> R,
-{
-    fn into_host_fn(self) -> HostFunc<Self, (A, B), R>
-    where
-        Self: Sized,
-    {
-        HostFunc(self, PhantomData)
-    }
-}
+for_all_tuples!(impl_into_host_fn);
 
-// adapted from https://play.rust-lang.org/?version=stable&mode=debug&edition=2018&gist=2d064fe8d7f579d0e59df9967861ee7a
-trait AssertZeroSized: Sized {
-    const ASSERT_ZERO_SIZED: () = [()][size_of::<Self>()];
+/// A host closure, with the slot it reports panics to.
+struct HostEnv<F> {
+    f: F,
+    panic_slot: *const PanicSlot,
 }
 
-impl<T: Sized> AssertZeroSized for T {}
-
 trait AsFnPtr<P, R> {
+    /// Pointer to an `extern "C"` function calling the closure, taking a pointer to its
+    /// [`HostEnv`] followed by its arguments. If the closure panics, the returned value is
+    /// uninitialized.
     fn as_fn_ptr() -> *const u8;
 }
 
 macro_rules! impl_as_fn_ptr {
     ($($ty:ident $(,)?)*) => {
-        #[allow(unused_parens)]
         #[allow(non_snake_case)]
-        impl<RET, FUN: Fn($($ty,)*) -> RET + Copy, $($ty),* > AsFnPtr<($($ty),*), RET> for FUN
+        impl<RET, FUN: Fn($($ty,)*) -> RET, $($ty),* > AsFnPtr<($($ty,)*), RET> for FUN
         where
             $($ty: Param),*
         {
             fn as_fn_ptr() -> *const u8 {
-                #[allow(clippy::let_unit_value, path_statements)]
-                FUN::ASSERT_ZERO_SIZED;
-
-                extern "C" fn tramp<FUN: Fn($($ty),*) -> RET, RET, $($ty,)*>($($ty: $ty),*)  -> RET {
-                    // F is zero-size, we can create it out of thin air
-                    let f: FUN = unsafe {
-                        #[allow(clippy::uninit_assumed_init)]
-                        MaybeUninit::uninit().assume_init()
-                    };
-                    f($($ty,)*)
+                extern "C" fn tramp<FUN: Fn($($ty),*) -> RET, RET, $($ty,)*>(
+                    env: *const HostEnv<FUN>,
+                    $($ty: $ty),*
+                ) -> MaybeUninit<RET> {
+                    // safety: the environment is owned by the ctx that owns the calling code
+                    let env = unsafe { &*env };
+                    let call = || in_host_closure(|| (env.f)($($ty,)*));
+                    match panic::catch_unwind(AssertUnwindSafe(call)) {
+                        Ok(ret) => MaybeUninit::new(ret),
+                        Err(payload) => {
+                            // safety: the slot is owned by the same ctx
+                            unsafe { (*env.panic_slot).set(payload) };
+                            MaybeUninit::uninit()
+                        }
+                    }
                 }
 
-                (tramp::<FUN , RET, $($ty),*> as extern "C" fn($($ty),*) -> RET) as *const u8
+                (tramp::<FUN, RET, $($ty),*>
+                    as extern "C" fn(*const HostEnv<FUN>, $($ty),*) -> MaybeUninit<RET>)
+                    as *const u8
             }
         }
-
     };
 }
 
-#[allow(unused_parens)]
-#[allow(non_snake_case)]
-impl<RET, FUN: Fn(A) -> RET + Copy, A> AsFnPtr<(A,), RET> for FUN
-where
-    A: Param,
-{
-    fn as_fn_ptr() -> *const u8 {
-        #[allow(clippy::let_unit_value, path_statements)]
-        FUN::ASSERT_ZERO_SIZED;
-        extern "C" fn tramp<FUN: Fn(A) -> RET, RET, A>(A: A) -> RET {
-            let f: FUN = unsafe {
-                #[allow(clippy::uninit_assumed_init)]
-                MaybeUninit::uninit().assume_init()
-            };
-            f(A)
+for_all_tuples!(impl_as_fn_ptr);
+
+/// Box the closure `f`, owned by the ctx, and return its address.
+fn host_env<F: 'static>(ctx: &mut FnCtx, f: F) -> *const HostEnv<F> {
+    let env = Box::new(HostEnv {
+        f,
+        panic_slot: ctx.panic_slot.unwrap_or(std::ptr::null()),
+    });
+    let ptr = &*env as *const HostEnv<F>;
+    ctx.host_envs.push(env);
+    ptr
+}
+
+macro_rules! impl_host_fn {
+    ($($ty:ident $(,)?)*) => {
+        impl<FUN, $($ty,)* RET> HostFn for HostFunc<FUN, ($($ty,)*), RET>
+        where
+            FUN: Fn($($ty),*) -> RET,
+            $($ty: Param,)*
+            RET: ScalarResults,
+        {
+            type Params = maybe_paren!($($ty),*);
+            type Returns = RET;
+
+            fn emit_call(
+                &self,
+                ctx: &mut FnCtx,
+                params: impl IntoParams<Input = Self::Params>,
+            ) -> RET::Results {
+                let ptr_ty = ctx.module().target_config().pointer_type();
+                let mut sig = ctx.module().make_signature();
+                // the closure environment
+                sig.params.push(AbiParam::new(ptr_ty));
+                $(
+                    $ty::to_abi_params(&mut sig.params);
+                )*
+                RET::to_abi_returns(&mut sig);
+                let sigref = ctx.builder().import_signature(sig);
+
+                if ctx.panic_slot.is_none() {
+                    // the addresses of the closure and its trampoline are only valid in the
+                    // current process, the function is rejected once emitted
+                    ctx.error.get_or_insert(LegoError::HostClosureInObject);
+                }
+                assert_eq!(
+                    self.module_id, ctx.module_id,
+                    "host function belongs to another context, or was freed by a reset"
+                );
+                let env = ctx.builder().ins().iconst(ptr_ty, self.env as usize as i64);
+                let fptr = ctx
+                    .builder()
+                    .ins()
+                    .iconst(ptr_ty, FUN::as_fn_ptr() as usize as i64);
+                let mut args = vec![env];
+                params.params(ctx, &mut args);
+                let ret = RET::Results::emit_call(ctx, args, |ctx, args| {
+                    ctx.builder().ins().call_indirect(sigref, fptr, args)
+                });
+                emit_panic_check(ctx);
+                ret
+            }
         }
-        (tramp::<FUN, RET, A> as extern "C" fn(A) -> RET) as *const u8
-    }
+    };
 }
-impl_as_fn_ptr!(A, B);
-impl_as_fn_ptr!(A, B, C);
-impl_as_fn_ptr!(A, B, C, D);
-impl_as_fn_ptr!(A, B, C, D, E);
-impl_as_fn_ptr!(A, B, C, D, E, F);
-impl_as_fn_ptr!(A, B, C, D, E, F, G);
 
-impl<F, A, R> HostFn for HostFunc<F, (A,), R>
-where
-    F: (Fn(A) -> R) + AsFnPtr<(A,), R>,
-    A: Param,
-    R: Results,
-{
-    type Params = A;
-    type Returns = R;
+for_all_tuples!(impl_host_fn);
 
-    fn emit_call(
-        &self,
-        ctx: &mut FnCtx,
-        params: impl IntoParams<Input = Self::Params>,
-    ) -> R::Results {
-        let ptr_ty = ctx.module().target_config().pointer_type();
-        let mut sig = ctx.module().make_signature();
-        A::to_abi_params(&mut sig.params);
-        R::to_abi_params(&mut sig.returns);
-        let sigref = ctx.builder().import_signature(sig);
-
-        let fptr = ctx
-            .builder()
-            .ins()
-            .iconst(ptr_ty, F::as_fn_ptr() as usize as i64);
-        let mut args = Vec::new();
-        params.params(ctx, &mut args);
-        let call = ctx.builder().ins().call_indirect(sigref, fptr, &args);
-        let results = ctx.builder().inst_results(call);
-        R::Results::from_func_ret(results)
+/// An `extern "C"` function that can be registered with [`host_fns!`](crate::host_fns), and
+/// imported by name with [`Ctx::import_func`].
+pub trait HostFnPtr {
+    type Params: Params;
+    type Returns: ScalarResults;
+
+    fn to_fn_ptr(self) -> *const u8;
+
+    /// Add the params and returns of the function to `sig`.
+    fn signature(sig: &mut Signature) {
+        Self::Params::to_abi_params(&mut sig.params);
+        Self::Returns::to_abi_returns(sig);
     }
 }
 
-impl<F, A, B, R> HostFn for HostFunc<F, (A, B), R>
-where
-    F: (Fn(A, B) -> R) + AsFnPtr<(A, B), R>,
-    A: Param,
-    B: Param,
-    R: Results,
-{
-    type Params = (A, B);
-    type Returns = R;
+impl<RET: ScalarResults> HostFnPtr for extern "C" fn() -> RET {
+    type Params = ();
+    type Returns = RET;
 
-    fn emit_call(
-        &self,
-        ctx: &mut FnCtx,
-        params: impl IntoParams<Input = Self::Params>,
-    ) -> R::Results {
-        let ptr_ty = ctx.module().target_config().pointer_type();
-        let mut sig = ctx.module().make_signature();
-        A::to_abi_params(&mut sig.params);
-        B::to_abi_params(&mut sig.params);
-        R::to_abi_params(&mut sig.returns);
-        let sigref = ctx.builder().import_signature(sig);
-
-        let fptr = ctx
-            .builder()
-            .ins()
-            .iconst(ptr_ty, F::as_fn_ptr() as usize as i64);
-        let mut args = Vec::new();
-        params.params(ctx, &mut args);
-        let call = ctx.builder().ins().call_indirect(sigref, fptr, &args);
-        let results = ctx.builder().inst_results(call);
-        R::Results::from_func_ret(results)
+    fn to_fn_ptr(self) -> *const u8 {
+        self as *const u8
     }
 }
 
+macro_rules! impl_host_fn_ptr {
+    ($($ty:ident $(,)?)*) => {
+        impl<$($ty: Param,)* RET: ScalarResults> HostFnPtr for extern "C" fn($($ty),*) -> RET {
+            type Params = maybe_paren!($($ty),*);
+            type Returns = RET;
+
+            fn to_fn_ptr(self) -> *const u8 {
+                self as *const u8
+            }
+        }
+    };
+}
+
+for_all_tuples!(impl_host_fn_ptr);
+
 pub trait IntoParams {
     type Input;
 
@@ -460,7 +709,9 @@ macro_rules! impl_params_tuples {
 
             #[allow(non_snake_case)]
             fn initialize(ctx: &mut FnCtx) -> Self::Values {
-                let mut idxs = (0..ctx.builder.block_params(ctx.current_block).len());
+                // the results pointer comes before the params
+                let first = ctx.ret_ptr.is_some() as usize;
+                let mut idxs = (first..ctx.builder.block_params(ctx.current_block).len());
                 $(
                     let $ty = $ty::initialize_param_at(ctx, &mut idxs);
                 )*
@@ -475,13 +726,25 @@ macro_rules! impl_params_tuples {
 
 for_all_tuples!(impl_params_tuples);
 
-pub trait FuncRet {
-    fn from_func_ret(vals: &[Value]) -> Self;
+pub trait FuncRet: Sized {
+    /// Emit a call to a function returning `Self`. `call` emits the call instruction with the
+    /// final arguments.
+    fn emit_call(
+        ctx: &mut FnCtx,
+        args: Vec<Value>,
+        call: impl FnOnce(&mut FnCtx, &[Value]) -> Inst,
+    ) -> Self;
     fn return_(self, ctx: &mut FnCtx);
 }
 
 impl<T> FuncRet for Val<T> {
-    fn from_func_ret(vals: &[Value]) -> Self {
+    fn emit_call(
+        ctx: &mut FnCtx,
+        args: Vec<Value>,
+        call: impl FnOnce(&mut FnCtx, &[Value]) -> Inst,
+    ) -> Self {
+        let call = call(ctx, &args);
+        let vals = ctx.builder.inst_results(call);
         assert_eq!(vals.len(), 1);
         Val::from_value(vals[0])
     }
@@ -492,8 +755,13 @@ impl<T> FuncRet for Val<T> {
 }
 
 impl FuncRet for () {
-    fn from_func_ret(vals: &[Value]) -> Self {
-        assert!(vals.is_empty());
+    fn emit_call(
+        ctx: &mut FnCtx,
+        args: Vec<Value>,
+        call: impl FnOnce(&mut FnCtx, &[Value]) -> Inst,
+    ) -> Self {
+        let call = call(ctx, &args);
+        assert!(ctx.builder.inst_results(call).is_empty());
     }
 
     fn return_(self, ctx: &mut FnCtx) {
@@ -501,38 +769,107 @@ impl FuncRet for () {
     }
 }
 
-pub trait Results: ToAbiParams {
+/// Size of the slot of each value of a tuple result.
+pub(crate) const RET_SLOT_SIZE: u32 = 8;
+
+macro_rules! impl_func_ret_tuples {
+    ($($ty:ident $(,)?)*) => {
+        impl<$($ty: Primitive,)*> FuncRet for ($(Val<$ty>,)*) {
+            #[allow(non_snake_case, unused_assignments)]
+            fn emit_call(
+                ctx: &mut FnCtx,
+                mut args: Vec<Value>,
+                call: impl FnOnce(&mut FnCtx, &[Value]) -> Inst,
+            ) -> Self {
+                let len = [$(stringify!($ty)),*].len() as u32;
+                let data = StackSlotData::new(
+                    StackSlotKind::ExplicitSlot,
+                    len * RET_SLOT_SIZE,
+                    RET_SLOT_SIZE.ilog2() as u8,
+                );
+                let slot = ctx.builder.create_sized_stack_slot(data);
+                let ptr_ty = ctx.module.target_config().pointer_type();
+                let addr = ctx.builder.ins().stack_addr(ptr_ty, slot, 0);
+                args.insert(0, addr);
+                call(ctx, &args);
+
+                let mut offset = 0;
+                $(
+                    let $ty = Val::from_value(ctx.builder.ins().stack_load($ty::ty(), slot, offset));
+                    offset += RET_SLOT_SIZE as i32;
+                )*
+                ($($ty,)*)
+            }
+
+            #[allow(non_snake_case, unused_assignments)]
+            fn return_(self, ctx: &mut FnCtx) {
+                let ret_ptr = ctx.ret_ptr.expect("function doesn't return a tuple");
+                let ($($ty,)*) = self;
+                let mut offset = 0;
+                $(
+                    ctx.builder.ins().store(MemFlags::trusted(), $ty.value(), ret_ptr, offset);
+                    offset += RET_SLOT_SIZE as i32;
+                )*
+                ctx.builder.ins().return_(&[]);
+            }
+        }
+    };
+}
+
+for_all_tuples!(impl_func_ret_tuples);
+
+/// The return type of a function.
+///
+/// A tuple is returned through a pointer passed as first argument, to a buffer holding each value
+/// in its own 8 bytes slot. This keeps the layout independent of the platform's conventions for
+/// returning structs.
+pub trait Results {
     type Results: FuncRet;
+    /// Whether the results are written through a pointer rather than returned.
+    const RET_PTR: bool = false;
+
+    fn to_abi_returns(sig: &mut Signature);
 }
 
+/// Results returned by value: a single primitive, or nothing. Host functions can only return
+/// these.
+pub trait ScalarResults: Results {}
+
 impl<T: Primitive + ToAbiParams> Results for T {
     type Results = Val<T>;
+
+    fn to_abi_returns(sig: &mut Signature) {
+        T::to_abi_params(&mut sig.returns);
+    }
 }
 
+impl<T: Primitive + ToAbiParams> ScalarResults for T {}
+
 impl Results for () {
     type Results = ();
-}
 
-pub trait Call<I, O> {
-    fn fn_call(self, input: I) -> O;
+    fn to_abi_returns(_sig: &mut Signature) {}
 }
 
-impl<F, A, O> Call<(A,), O> for F
-where
-    F: FnMut(A) -> O,
-{
-    fn fn_call(mut self, (input,): (A,)) -> O {
-        (self)(input)
-    }
+impl ScalarResults for () {}
+
+macro_rules! impl_results_tuples {
+    ($($ty:ident $(,)?)*) => {
+        impl<$($ty: Primitive + ToAbiParams,)*> Results for ($($ty,)*) {
+            type Results = ($(Val<$ty>,)*);
+            const RET_PTR: bool = true;
+
+            fn to_abi_returns(sig: &mut Signature) {
+                sig.params.insert(0, AbiParam::new(<*mut u8>::ty()));
+            }
+        }
+    };
 }
 
-impl<F, A, B, O> Call<(A, B), O> for F
-where
-    F: FnMut(A, B) -> O,
-{
-    fn fn_call(mut self, (a, b): (A, B)) -> O {
-        (self)(a, b)
-    }
+for_all_tuples!(impl_results_tuples);
+
+pub trait Call<I, O> {
+    fn fn_call(self, input: I) -> O;
 }
 
 impl<F, O> Call<(), O> for F
@@ -544,18 +881,23 @@ where
     }
 }
 
-impl<F, A, O, I> Call<I, <<Self as HostFn>::Returns as Results>::Results> for HostFunc<F, (A,), O>
-where
-    Self: HostFn,
-    I: IntoParams<Input = <Self as HostFn>::Params>,
-{
-    fn fn_call(self, p: I) -> <<Self as HostFn>::Returns as Results>::Results {
-        self.call(p)
-    }
+macro_rules! impl_call {
+    ($($ty:ident $(,)?)*) => {
+        impl<FUN, $($ty,)* O> Call<($($ty,)*), O> for FUN
+        where
+            FUN: FnMut($($ty),*) -> O,
+        {
+            #[allow(non_snake_case)]
+            fn fn_call(mut self, ($($ty,)*): ($($ty,)*)) -> O {
+                (self)($($ty),*)
+            }
+        }
+    };
 }
 
-impl<F, A, B, O, I> Call<I, <<Self as HostFn>::Returns as Results>::Results>
-    for HostFunc<F, (A, B), O>
+for_all_tuples!(impl_call);
+
+impl<F, P, O, I> Call<I, <<Self as HostFn>::Returns as Results>::Results> for HostFunc<F, P, O>
 where
     Self: HostFn,
     I: IntoParams<Input = <Self as HostFn>::Params>,
@@ -1,11 +1,17 @@
-use std::ops::Range;
-
-use cranelift::prelude::InstBuilder;
-
-use crate::cmp::Compare;
-use crate::control_flow::BlockRet;
-use crate::func::with_ctx;
-use crate::prelude::Primitive;
+use std::ops::{Range, RangeInclusive};
+
+use cranelift::prelude::types::{F32, F64};
+use cranelift::prelude::{InstBuilder, IntCC, TrapCode};
+
+use crate::arithmetic::{IntAdd, IntMul};
+use crate::cmp::{Comparable, Compare};
+use crate::control_flow::select::select;
+use crate::control_flow::{BlockRet, LoopFrame};
+use crate::for_all_integers;
+use crate::func::{with_ctx, FnCtx};
+use crate::option::StagedOption;
+use crate::prelude::{Integer, Primitive};
+use crate::proxy::{Ref, RefMut};
 use crate::val::{AsVal, Val};
 use crate::var::Var;
 
@@ -30,6 +36,78 @@ pub trait JIterator {
         Filter { inner: self, f }
     }
 
+    /// Pair each item with its index.
+    fn enumerate(self) -> Enumerate<Self>
+    where
+        Self: Sized,
+    {
+        Enumerate {
+            inner: self,
+            count: Var::new(0usize),
+        }
+    }
+
+    /// Iterate over both iterators at once, until either is exhausted.
+    fn zip<U>(self, other: U) -> Zip<Self, U::Iter>
+    where
+        U: IntoJiter,
+        Self: Sized,
+    {
+        Zip {
+            a: self,
+            b: other.into_jiter(),
+        }
+    }
+
+    /// The first `n` items.
+    fn take(self, n: impl AsVal<Ty = usize>) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take {
+            inner: self,
+            n: n.value(),
+            taken: Var::new(0usize),
+        }
+    }
+
+    /// The items after the first `n`.
+    fn skip(self, n: impl AsVal<Ty = usize>) -> Skip<Self>
+    where
+        Self: Sized,
+    {
+        Skip {
+            inner: self,
+            n: n.value(),
+            seen: Var::new(0usize),
+        }
+    }
+
+    /// The first item, and then every `step` items. The function traps with
+    /// [`Trap::DivisionByZero`](crate::prelude::Trap) if `step` is zero.
+    fn step_by(self, step: impl AsVal<Ty = usize>) -> StepBy<Self>
+    where
+        Self: Sized,
+    {
+        StepBy {
+            inner: self,
+            step: step.value(),
+            seen: Var::new(0usize),
+        }
+    }
+
+    /// The items of `self`, and then the items of `other`.
+    fn chain<U>(self, other: U) -> Chain<Self, U::Iter>
+    where
+        U: IntoJiter<Item = Self::Item>,
+        Self: Sized,
+    {
+        Chain {
+            a: self,
+            b: other.into_jiter(),
+        }
+    }
+
     fn for_each<F>(self, f: F)
     where
         F: FnOnce(Self::Item),
@@ -77,12 +155,25 @@ pub trait JIterator {
 
             ctx.builder().switch_to_block(body);
             ctx.builder().seal_block(body);
-            <B>::read_from_ret(&mut ctx.builder().block_params(body).iter().copied())
+            let acc = <B>::read_from_ret(&mut ctx.builder().block_params(body).iter().copied());
+
+            // `continue` moves on to the next item, leaving the accumulator untouched, and
+            // `break` exits the loop with the value it's passed as accumulator.
+            let mut continue_args = Vec::new();
+            acc.to_block_values(&mut continue_args);
+            ctx.loops.push(LoopFrame {
+                header,
+                exit,
+                continue_args,
+            });
+
+            acc
         });
 
         let acc = f(acc, it());
 
         with_ctx(|ctx| {
+            ctx.loops.pop();
             let mut params = Vec::new();
             acc.to_block_values(&mut params);
             ctx.builder().ins().jump(header, &params);
@@ -93,6 +184,291 @@ pub trait JIterator {
             B::read_from_ret(&mut ctx.builder().block_params(exit).iter().copied())
         })
     }
+
+    /// Like [`JIterator::fold`], but `f` also tells whether to stop: it returns `(done, acc)`,
+    /// and the loop exits as soon as `done` is true, without looking at the remaining items.
+    /// Returns whether the loop exited early, and the accumulator.
+    fn try_fold<F, B>(mut self, init: B, f: F) -> (Val<bool>, B)
+    where
+        B: BlockRet,
+        F: FnOnce(B, Self::Item) -> (Val<bool>, B),
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        let [header, body, break_block, exit] = with_ctx(|ctx| {
+            let [header, body, break_block, exit] = ctx.create_blocks();
+            B::push_param_ty(ctx, header);
+            B::push_param_ty(ctx, body);
+            B::push_param_ty(ctx, break_block);
+            <(Val<bool>, B)>::push_param_ty(ctx, exit);
+            let mut params = Vec::new();
+            init.to_block_values(&mut params);
+            ctx.builder().ins().jump(header, &params);
+            ctx.builder().switch_to_block(header);
+            [header, body, break_block, exit]
+        });
+
+        let (has_it, it) = self.next();
+
+        let acc = with_ctx(|ctx| {
+            let acc = B::read_from_ret(&mut ctx.builder().block_params(header).iter().copied());
+            let mut then_params = Vec::new();
+            acc.to_block_values(&mut then_params);
+            let mut else_params = Vec::new();
+            // exhausted, `has_it` is false
+            (has_it, acc).to_block_values(&mut else_params);
+            ctx.builder()
+                .ins()
+                .brif(has_it.value, body, &then_params, exit, &else_params);
+
+            ctx.builder().switch_to_block(body);
+            ctx.builder().seal_block(body);
+            let acc = B::read_from_ret(&mut ctx.builder().block_params(body).iter().copied());
+
+            // `break` stops the loop like `done` does, with the value it's passed as accumulator
+            let mut continue_args = Vec::new();
+            acc.to_block_values(&mut continue_args);
+            ctx.loops.push(LoopFrame {
+                header,
+                exit: break_block,
+                continue_args,
+            });
+
+            acc
+        });
+
+        let (done, acc) = f(acc, it());
+
+        with_ctx(|ctx| {
+            ctx.loops.pop();
+            let mut else_params = Vec::new();
+            acc.to_block_values(&mut else_params);
+            let mut then_params = Vec::new();
+            done.to_block_values(&mut then_params);
+            then_params.extend_from_slice(&else_params);
+            ctx.builder()
+                .ins()
+                .brif(done.value, exit, &then_params, header, &else_params);
+            ctx.builder().seal_block(header);
+
+            ctx.builder().switch_to_block(break_block);
+            ctx.builder().seal_block(break_block);
+            let mut params = vec![ctx.builder().ins().iconst(bool::ty(), 1)];
+            params.extend_from_slice(ctx.builder().block_params(break_block));
+            ctx.builder().ins().jump(exit, &params);
+
+            ctx.builder().switch_to_block(exit);
+            ctx.builder().seal_block(exit);
+            <(Val<bool>, B)>::read_from_ret(&mut ctx.builder().block_params(exit).iter().copied())
+        })
+    }
+
+    /// Whether `f` is true for any item, stopping at the first one it is true for.
+    fn any<F>(self, f: F) -> Val<bool>
+    where
+        F: FnOnce(Self::Item) -> Val<bool>,
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        self.try_fold((), |(), it| (f(it), ())).0
+    }
+
+    /// Whether `f` is true for every item, stopping at the first one it is false for.
+    fn all<F>(self, f: F) -> Val<bool>
+    where
+        F: FnOnce(Self::Item) -> Val<bool>,
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        !self.try_fold((), |(), it| (!f(it), ())).0
+    }
+
+    /// The first item `f` is true for.
+    fn find<F>(self, f: F) -> StagedOption<Self::Item>
+    where
+        F: FnOnce(&Self::Item) -> Val<bool>,
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        let (found, it) = self.try_fold(null::<Self::Item>(), |_, it| (f(&it), it));
+        StagedOption::new(found, it)
+    }
+
+    /// The index of the first item `f` is true for.
+    fn position<F>(self, f: F) -> StagedOption<Val<usize>>
+    where
+        F: FnOnce(Self::Item) -> Val<bool>,
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        let (found, idx) = self
+            .enumerate()
+            .try_fold(Val::new(0usize), |_, (idx, it)| (f(it), idx));
+        StagedOption::new(found, idx)
+    }
+
+    /// The sum of the items, zero if there are none.
+    fn sum<T>(self) -> Val<T>
+    where
+        T: IntAdd,
+        Self: Sized,
+        Self::Item: ItemValue<Ty = T> + BlockRet,
+    {
+        self.fold(null::<Val<T>>(), |acc, it| {
+            let it = it.item_value();
+            with_ctx(|ctx| Val::from_value(T::perform(ctx, acc.value(), it.value())))
+        })
+    }
+
+    /// The product of the items, one if there are none.
+    fn product<T>(self) -> Val<T>
+    where
+        T: IntMul,
+        Self: Sized,
+        Self::Item: ItemValue<Ty = T> + BlockRet,
+    {
+        let one = with_ctx(|ctx| {
+            let ty = T::ty();
+            let one = match ty {
+                F32 => ctx.builder().ins().f32const(1.0),
+                F64 => ctx.builder().ins().f64const(1.0),
+                _ => ctx.builder().ins().iconst(ty, 1),
+            };
+            Val::from_value(one)
+        });
+        self.fold(one, |acc, it| {
+            let it = it.item_value();
+            with_ctx(|ctx| Val::from_value(T::perform(ctx, acc.value(), it.value())))
+        })
+    }
+
+    /// The number of items.
+    fn count(self) -> Val<usize>
+    where
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        self.fold(Val::new(0usize), |acc, _| acc + 1usize)
+    }
+
+    /// The smallest item, none if there are no items.
+    fn min<T>(self) -> StagedOption<Val<T>>
+    where
+        T: Comparable,
+        Self: Sized,
+        Self::Item: ItemValue<Ty = T> + BlockRet,
+    {
+        reduce_with(self, |acc, it| acc.min(it))
+    }
+
+    /// The largest item, none if there are no items.
+    fn max<T>(self) -> StagedOption<Val<T>>
+    where
+        T: Comparable,
+        Self: Sized,
+        Self::Item: ItemValue<Ty = T> + BlockRet,
+    {
+        reduce_with(self, |acc, it| acc.max(it))
+    }
+
+    /// The item with the smallest key, the first one if several are minimal.
+    fn min_by_key<F, K>(self, f: F) -> StagedOption<Self::Item>
+    where
+        F: FnOnce(&Self::Item) -> Val<K>,
+        K: Comparable,
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        select_by_key(self, f, |key, best| key.lt(best))
+    }
+
+    /// The item with the largest key, the last one if several are maximal.
+    fn max_by_key<F, K>(self, f: F) -> StagedOption<Self::Item>
+    where
+        F: FnOnce(&Self::Item) -> Val<K>,
+        K: Comparable,
+        Self: Sized,
+        Self::Item: BlockRet,
+    {
+        select_by_key(self, f, |key, best| key.ge(best))
+    }
+}
+
+/// Items holding a primitive value, that reductions like [`JIterator::sum`] operate on.
+pub trait ItemValue {
+    type Ty: Primitive;
+
+    fn item_value(&self) -> Val<Self::Ty>;
+}
+
+impl<T: Primitive> ItemValue for Val<T> {
+    type Ty = T;
+
+    fn item_value(&self) -> Val<T> {
+        *self
+    }
+}
+
+impl<T: Primitive> ItemValue for Ref<'_, T> {
+    type Ty = T;
+
+    fn item_value(&self) -> Val<T> {
+        self.get()
+    }
+}
+
+impl<T: Primitive> ItemValue for RefMut<'_, T> {
+    type Ty = T;
+
+    fn item_value(&self) -> Val<T> {
+        self.get()
+    }
+}
+
+/// Fold the items with `f`, starting from the first one.
+fn reduce_with<I, T>(iter: I, f: impl FnOnce(Val<T>, Val<T>) -> Val<T>) -> StagedOption<Val<T>>
+where
+    I: JIterator,
+    I::Item: ItemValue<Ty = T> + BlockRet,
+    T: Primitive,
+{
+    let init = (Val::new(false), null::<Val<T>>());
+    let (seen, acc) = iter.fold(init, |(seen, acc), it| {
+        let it = it.item_value();
+        (Val::new(true), select(seen, f(acc, it), it))
+    });
+    StagedOption::new(seen, acc)
+}
+
+/// The item whose key is `better` than the keys of all the items before it.
+fn select_by_key<I, F, K>(
+    iter: I,
+    f: F,
+    better: impl FnOnce(Val<K>, Val<K>) -> Val<bool>,
+) -> StagedOption<I::Item>
+where
+    I: JIterator,
+    I::Item: BlockRet,
+    F: FnOnce(&I::Item) -> Val<K>,
+    K: Primitive,
+{
+    let init = (Val::new(false), null::<Val<K>>(), null::<I::Item>());
+    let (seen, _, best) = iter.fold(init, |(seen, best_key, best), it| {
+        let key = f(&it);
+        let take = !seen | better(key, best_key);
+        let (key, it) = select(take, (key, it), (best_key, best));
+        (Val::new(true), key, it)
+    });
+    StagedOption::new(seen, best)
+}
+
+/// A placeholder value, for accumulators that start out without a meaningful value.
+fn null<B: BlockRet>() -> B {
+    with_ctx(|ctx| {
+        let mut values = Vec::new();
+        B::null(ctx, &mut values);
+        B::read_from_ret(&mut values.into_iter())
+    })
 }
 
 pub struct Map<T, F> {
@@ -121,62 +497,215 @@ pub trait IntoJiter {
     fn into_jiter(self) -> Self::Iter;
 }
 
-pub struct RangeJiter<Idx> {
-    start: Var<Idx>,
-    end: Val<Idx>,
+impl<I: JIterator> IntoJiter for I {
+    type Iter = I;
+    type Item = I::Item;
+
+    fn into_jiter(self) -> Self::Iter {
+        self
+    }
 }
 
-trait Step {
-    fn step(&mut self);
+/// Integers that ranges can iterate over.
+pub trait Step: Integer + Comparable {}
+
+macro_rules! impl_step {
+    ($ty:ident) => {
+        impl Step for $ty {}
+    };
 }
 
-impl Step for Var<usize> {
-    fn step(&mut self) {
-        *self += 1usize;
-    }
+for_all_integers!(impl_step);
+
+/// Iterates over a range, possibly in reverse or stepping over items.
+///
+/// The range tracks its last item rather than its end, so that inclusive ranges ending at the
+/// largest value of `Idx` don't overflow.
+pub struct RangeJiter<Idx> {
+    /// the next item
+    next: Var<Idx>,
+    /// the last item, in iteration order. Only meaningful if the range isn't empty.
+    last: Val<Idx>,
+    /// whether all the items were yielded
+    done: Var<bool>,
+    /// `None` for a step of one. Zero for a step too large for `Idx`, which goes past any item.
+    step: Option<Val<Idx>>,
+    /// whether the range is iterated from its end
+    rev: bool,
 }
 
-impl Step for Var<u64> {
-    fn step(&mut self) {
-        *self += 1u64;
+impl<Idx: Step> RangeJiter<Idx> {
+    fn new(first: Val<Idx>, last: Val<Idx>, empty: Val<bool>) -> Self {
+        RangeJiter {
+            next: Var::new(first),
+            last,
+            done: Var::new(empty),
+            step: None,
+            rev: false,
+        }
+    }
+
+    /// Iterate from the last item to the first.
+    ///
+    /// A stepped range is reversed from the last item its step reaches, e.g.
+    /// `(0..10).into_jiter().step(4).rev()` yields 8, 4 and 0.
+    pub fn rev(self) -> Self {
+        let first = self.next.value();
+        let last = match self.step {
+            None => self.last,
+            Some(step) => with_ctx(|ctx| {
+                let dist = distance(ctx, first, self.last, self.rev);
+                let b = ctx.builder();
+                // only the first item is reached with a step of zero
+                let is_zero = b.ins().icmp_imm(IntCC::Equal, step.value(), 0);
+                let one = b.ins().iconst(Idx::ty(), 1);
+                let divisor = b.ins().select(is_zero, one, step.value());
+                let rem = b.ins().urem(dist.value(), divisor);
+                let dist = b.ins().isub(dist.value(), rem);
+                let zero = b.ins().iconst(Idx::ty(), 0);
+                let dist = b.ins().select(is_zero, zero, dist);
+                advance(ctx, first, Val::from_value(dist), self.rev)
+            }),
+        };
+        RangeJiter {
+            next: Var::new(last),
+            last: first,
+            done: self.done,
+            step: self.step,
+            rev: !self.rev,
+        }
     }
+
+    /// The first item, and then every `step` items. `step` is taken as unsigned. The function
+    /// traps with [`Trap::DivisionByZero`](crate::prelude::Trap) if `step` is zero.
+    ///
+    /// Contrary to [`JIterator::step_by`], the step is in the type of the range, and the items
+    /// in between are skipped without being visited.
+    pub fn step(self, step: impl AsVal<Ty = Idx>) -> Self {
+        let step = step.value();
+        let step = with_ctx(|ctx| {
+            let b = ctx.builder();
+            b.ins()
+                .trapz(step.value(), TrapCode::INTEGER_DIVISION_BY_ZERO);
+            match self.step {
+                // a step that overflows goes past any item, it becomes zero rather than wrapping
+                Some(prev) => {
+                    let (steps, overflow) = b.ins().umul_overflow(prev.value(), step.value());
+                    let zero = b.ins().iconst(Idx::ty(), 0);
+                    Val::from_value(b.ins().select(overflow, zero, steps))
+                }
+                None => step,
+            }
+        });
+        RangeJiter {
+            step: Some(step),
+            ..self
+        }
+    }
+}
+
+/// Number of steps of one from `from` to `to`, going down if `rev`. Exact when taken as
+/// unsigned, provided `to` comes after `from`.
+fn distance<Idx: Step>(ctx: &mut FnCtx, from: Val<Idx>, to: Val<Idx>, rev: bool) -> Val<Idx> {
+    let (a, b) = if rev { (from, to) } else { (to, from) };
+    Val::from_value(ctx.builder().ins().isub(a.value(), b.value()))
+}
+
+fn advance<Idx: Step>(ctx: &mut FnCtx, from: Val<Idx>, by: Val<Idx>, rev: bool) -> Val<Idx> {
+    let b = ctx.builder();
+    let moved = if rev {
+        b.ins().isub(from.value(), by.value())
+    } else {
+        b.ins().iadd(from.value(), by.value())
+    };
+    Val::from_value(moved)
 }
 
-impl Step for Var<i32> {
-    fn step(&mut self) {
-        *self += 1i32;
+impl<Idx: Step> JIterator for RangeJiter<Idx> {
+    type Item = Val<Idx>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let mut next = self.next;
+        let mut done = self.done;
+        let last = self.last;
+        let step = self.step;
+        let rev = self.rev;
+        let has_it = !done.value();
+        (has_it, move || {
+            let it = next.value();
+            with_ctx(|ctx| {
+                let step = match step {
+                    Some(step) => step,
+                    None => Val::from_value(ctx.builder().ins().iconst(Idx::ty(), 1)),
+                };
+                // the last item is reached once less than a step away from it. A step of zero
+                // wraps around to the largest distance.
+                let remaining = distance(ctx, it, last, rev);
+                let b = ctx.builder();
+                let one = b.ins().iconst(Idx::ty(), 1);
+                let max_remaining = b.ins().isub(step.value(), one);
+                let is_last = b.ins().icmp(
+                    IntCC::UnsignedLessThanOrEqual,
+                    remaining.value(),
+                    max_remaining,
+                );
+                done.assign_ctx(ctx, Val::<bool>::from_value(is_last));
+                let moved = advance(ctx, it, step, rev);
+                next.assign_ctx(ctx, moved);
+            });
+            it
+        })
     }
 }
 
-impl<Idx> JIterator for RangeJiter<Idx>
+/// Iterate over `start..end`, for bounds that can't be written as a [`Range`] because one is a
+/// host integer and the other a staged one, e.g. `range(0usize, len)`.
+pub fn range<Idx: Step>(start: impl AsVal<Ty = Idx>, end: impl AsVal<Ty = Idx>) -> RangeJiter<Idx> {
+    let start = start.value();
+    let end = end.value();
+    let empty = start.ge(end);
+    // wraps around for an empty range, but isn't used then
+    let last = with_ctx(|ctx| {
+        let one = ctx.builder().ins().iconst(Idx::ty(), 1);
+        Val::from_value(ctx.builder().ins().isub(end.value(), one))
+    });
+    RangeJiter::new(start, last, empty)
+}
+
+/// Iterate over `start..=end`, see [`range`].
+pub fn range_inclusive<Idx: Step>(
+    start: impl AsVal<Ty = Idx>,
+    end: impl AsVal<Ty = Idx>,
+) -> RangeJiter<Idx> {
+    let start = start.value();
+    let end = end.value();
+    RangeJiter::new(start, end, start.gt(end))
+}
+
+impl<B, Idx> IntoJiter for Range<B>
 where
-    Val<Idx>: Compare,
-    Var<Idx>: Step,
+    B: AsVal<Ty = Idx>,
+    Idx: Step,
 {
+    type Iter = RangeJiter<Idx>;
     type Item = Val<Idx>;
 
-    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
-        let val = self.start.value();
-        let ret = (self.start.value().neq(self.end), move || val);
-        self.start.step();
-        ret
+    fn into_jiter(self) -> Self::Iter {
+        range(self.start, self.end)
     }
 }
 
-impl<Idx> IntoJiter for Range<Idx>
+impl<B, Idx> IntoJiter for RangeInclusive<B>
 where
-    Val<Idx>: Compare,
-    Var<Idx>: Step,
-    Idx: Primitive + AsVal<Ty = Idx>,
+    B: AsVal<Ty = Idx>,
+    Idx: Step,
 {
     type Iter = RangeJiter<Idx>;
     type Item = Val<Idx>;
 
     fn into_jiter(self) -> Self::Iter {
-        RangeJiter {
-            start: Var::new(self.start),
-            end: Val::new(self.end),
-        }
+        let (start, end) = self.into_inner();
+        range_inclusive(start, end)
     }
 }
 
@@ -194,53 +723,286 @@ where
     type Item = T::Item;
 
     fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
-        let [header_block, body_block, exit_block] = with_ctx(|ctx| {
-            let [header_block, body_block, exit_block] = ctx.create_blocks();
+        let (has_it, it) = next_matching(&mut self.inner, |it| (self.f)(it));
+        (has_it, || it)
+    }
+}
+
+/// Advance `inner` until an item satisfies `pred`, in a loop of its own. Returns whether an item
+/// was found, and the item, null if none was.
+fn next_matching<I>(inner: &mut I, pred: impl FnOnce(&I::Item) -> Val<bool>) -> (Val<bool>, I::Item)
+where
+    I: JIterator,
+    I::Item: BlockRet,
+{
+    let [header_block, body_block, exit_block] = with_ctx(|ctx| {
+        let [header_block, body_block, exit_block] = ctx.create_blocks();
+        <(Val<bool>, I::Item) as BlockRet>::push_param_ty(ctx, exit_block);
+        ctx.builder().ins().jump(header_block, &[]);
+        ctx.builder().switch_to_block(header_block);
+        [header_block, body_block, exit_block]
+    });
+
+    let (has_it, it) = inner.next();
+
+    with_ctx(|ctx| {
+        let then_params = Vec::new();
+        let mut else_params = Vec::new();
+        has_it.to_block_values(&mut else_params);
+        I::Item::null(ctx, &mut else_params);
+
+        ctx.builder().ins().brif(
+            has_it.value(),
+            body_block,
+            &then_params,
+            exit_block,
+            &else_params
+        );
+
+        ctx.builder().switch_to_block(body_block);
+        ctx.builder().seal_block(body_block);
+    });
+
+    let it = it();
+    let take = pred(&it);
+
+    with_ctx(|ctx| {
+        let mut then_params = Vec::new();
+        (has_it, it).to_block_values(&mut then_params);
+        ctx.builder().ins().brif(
+            take.value(),
+            exit_block,
+            &then_params,
+            header_block,
+            &[],
+        );
+
+        ctx.builder().seal_block(header_block);
+        ctx.builder().switch_to_block(exit_block);
+        ctx.builder().seal_block(exit_block);
+        <_ as BlockRet>::read_from_ret(&mut ctx.builder.block_params(exit_block).iter().copied())
+    })
+}
+
+/// Advance `inner` only if `cond` holds, and produce its item in that case.
+fn next_if<I>(cond: Val<bool>, inner: &mut I) -> (Val<bool>, I::Item)
+where
+    I: JIterator,
+    I::Item: BlockRet,
+{
+    let [body_block, exit_block] = with_ctx(|ctx| {
+        let [next_block, body_block, exit_block] = ctx.create_blocks();
+        <(Val<bool>, I::Item) as BlockRet>::push_param_ty(ctx, exit_block);
+        let mut else_params = Vec::new();
+        <(Val<bool>, I::Item) as BlockRet>::null(ctx, &mut else_params);
+        ctx.builder()
+            .ins()
+            .brif(cond.value(), next_block, &[], exit_block, &else_params);
+        ctx.builder().switch_to_block(next_block);
+        ctx.builder().seal_block(next_block);
+        [body_block, exit_block]
+    });
+
+    let (has_it, it) = inner.next();
+
+    with_ctx(|ctx| {
+        let mut else_params = Vec::new();
+        <(Val<bool>, I::Item) as BlockRet>::null(ctx, &mut else_params);
+        ctx.builder()
+            .ins()
+            .brif(has_it.value(), body_block, &[], exit_block, &else_params);
+        ctx.builder().switch_to_block(body_block);
+        ctx.builder().seal_block(body_block);
+    });
+
+    let it = it();
+
+    with_ctx(|ctx| {
+        let mut params = Vec::new();
+        (has_it, it).to_block_values(&mut params);
+        ctx.builder().ins().jump(exit_block, &params);
+        ctx.builder().switch_to_block(exit_block);
+        ctx.builder().seal_block(exit_block);
+        <_ as BlockRet>::read_from_ret(&mut ctx.builder().block_params(exit_block).iter().copied())
+    })
+}
+
+pub struct Enumerate<T> {
+    inner: T,
+    count: Var<usize>,
+}
+
+impl<T: JIterator> JIterator for Enumerate<T> {
+    type Item = (Val<usize>, T::Item);
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let mut count = self.count;
+        let (has_it, it) = self.inner.next();
+        (has_it, move || {
+            let idx = count.value();
+            count += 1usize;
+            (idx, it())
+        })
+    }
+}
+
+pub struct Zip<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> JIterator for Zip<A, B>
+where
+    A: JIterator,
+    B: JIterator,
+    B::Item: BlockRet,
+{
+    type Item = (A::Item, B::Item);
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let (has_a, a) = self.a.next();
+        // like `Iterator::zip`, `b` isn't advanced once `a` is exhausted
+        let (has_b, b) = next_if(has_a, &mut self.b);
+        (has_b, move || (a(), b))
+    }
+}
+
+pub struct Take<T> {
+    inner: T,
+    n: Val<usize>,
+    taken: Var<usize>,
+}
+
+impl<T> JIterator for Take<T>
+where
+    T: JIterator,
+    T::Item: BlockRet,
+{
+    type Item = T::Item;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let mut taken = self.taken;
+        // the inner iterator isn't advanced past the `n` items
+        let (has_it, it) = next_if(taken.lt(self.n), &mut self.inner);
+        (has_it, move || {
+            taken += 1usize;
+            it
+        })
+    }
+}
+
+pub struct Skip<T> {
+    inner: T,
+    n: Val<usize>,
+    seen: Var<usize>,
+}
+
+impl<T> JIterator for Skip<T>
+where
+    T: JIterator,
+    T::Item: BlockRet,
+{
+    type Item = T::Item;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let mut seen = self.seen;
+        let n = self.n;
+        let (has_it, it) = next_matching(&mut self.inner, |_| {
+            let keep = seen.ge(n);
+            seen += 1usize;
+            keep
+        });
+        (has_it, || it)
+    }
+}
+
+pub struct StepBy<T> {
+    inner: T,
+    step: Val<usize>,
+    seen: Var<usize>,
+}
+
+impl<T> JIterator for StepBy<T>
+where
+    T: JIterator,
+    T::Item: BlockRet,
+{
+    type Item = T::Item;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let mut seen = self.seen;
+        let step = self.step;
+        let (has_it, it) = next_matching(&mut self.inner, |_| {
+            let keep = (seen % step).eq(0usize);
+            seen += 1usize;
+            keep
+        });
+        (has_it, || it)
+    }
+}
+
+pub struct Chain<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> JIterator for Chain<A, B>
+where
+    A: JIterator,
+    B: JIterator<Item = A::Item>,
+    A::Item: BlockRet,
+{
+    type Item = A::Item;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let (has_a, a) = self.a.next();
+
+        // the items of `b` are only looked at once `a` is exhausted
+        let [b_block, b_body_block, exit_block] = with_ctx(|ctx| {
+            let [a_block, b_block, b_body_block, exit_block] = ctx.create_blocks();
             <(Val<bool>, Self::Item) as BlockRet>::push_param_ty(ctx, exit_block);
-            // Self::Item::push_param_ty(ctx, body_block);
-            ctx.builder().ins().jump(header_block, &[]);
-            ctx.builder().switch_to_block(header_block);
-            [header_block, body_block, exit_block]
+            ctx.builder()
+                .ins()
+                .brif(has_a.value(), a_block, &[], b_block, &[]);
+            ctx.builder().switch_to_block(a_block);
+            ctx.builder().seal_block(a_block);
+            [b_block, b_body_block, exit_block]
         });
-        
-        let (has_it, it) = self.inner.next();
-        
+
+        let a = a();
+
+        with_ctx(|ctx| {
+            let mut params = Vec::new();
+            (has_a, a).to_block_values(&mut params);
+            ctx.builder().ins().jump(exit_block, &params);
+            ctx.builder().switch_to_block(b_block);
+            ctx.builder().seal_block(b_block);
+        });
+
+        let (has_b, b) = self.b.next();
+
         with_ctx(|ctx| {
-            let then_params = Vec::new();
             let mut else_params = Vec::new();
-            has_it.to_block_values(&mut else_params);
+            has_b.to_block_values(&mut else_params);
             Self::Item::null(ctx, &mut else_params);
-
-            ctx.builder().ins().brif(
-                has_it.value(),
-                body_block,
-                &then_params,
-                exit_block,
-                &else_params
-            );
-        
-            ctx.builder().switch_to_block(body_block);
-            ctx.builder().seal_block(body_block);
+            ctx.builder()
+                .ins()
+                .brif(has_b.value(), b_body_block, &[], exit_block, &else_params);
+            ctx.builder().switch_to_block(b_body_block);
+            ctx.builder().seal_block(b_body_block);
         });
-        
-        let it = it();
-        let take = (self.f)(&it);
-        
+
+        let b = b();
+
         with_ctx(|ctx| {
-            let mut then_params = Vec::new();
-            (has_it, it).to_block_values(&mut then_params);
-            ctx.builder().ins().brif(
-                take.value(),
-                exit_block,
-                &then_params,
-                header_block,
-                &[],
-            );
-        
-            ctx.builder().seal_block(header_block);
+            let mut params = Vec::new();
+            (has_b, b).to_block_values(&mut params);
+            ctx.builder().ins().jump(exit_block, &params);
             ctx.builder().switch_to_block(exit_block);
             ctx.builder().seal_block(exit_block);
-            let (has_it, it) =  <_ as BlockRet>::read_from_ret(&mut ctx.builder.block_params(exit_block).iter().copied());
+            let (has_it, it) = <(Val<bool>, Self::Item) as BlockRet>::read_from_ret(
+                &mut ctx.builder().block_params(exit_block).iter().copied(),
+            );
             (has_it, || it)
         })
     }
@@ -1,41 +1,53 @@
 mod abi_params;
 mod arithmetic;
+mod backend;
 mod cmp;
 mod control_flow;
 mod ctx;
+mod error;
 pub mod ffi;
 mod func;
 mod iterator;
 mod macros;
+mod option;
 mod primitive;
 mod proxy;
 mod refs;
 mod slice;
+mod trap;
 mod val;
 mod var;
 mod vec;
 
 pub mod prelude {
-    // pub use crate::control_flow::if_then_else::{If, FlowControl, ControlFlow, IfCtx};
-    pub use crate::cmp::Compare;
+    pub use crate::control_flow::if_then_else::{If, IfCtx};
+    pub use crate::control_flow::select::select;
+    pub use crate::control_flow::switch::{Match, MatchCtx};
+    pub use crate::control_flow::{ControlFlow, FlowControl, IntoCond};
+    pub use crate::cmp::{Comparable, Compare, StagedPartialEq, StagedPartialOrd};
     pub use crate::val::{AsVal, Val};
     pub use crate::var::Var;
 
-    // pub use crate::control_flow::while_loop::{do_while, WhileCtx};
+    pub use crate::control_flow::while_loop::{do_loop, do_while, LoopBreak, WhileCtx};
     pub use crate::abi_params::ToAbiParams;
-    pub use crate::primitive::Primitive;
+    pub use crate::primitive::{Integer, Primitive};
+    pub use crate::iterator::{range, range_inclusive, Step};
 
     pub use crate::proxy::{Proxy, Ref, RefMut};
-    pub use crate::slice::Slice;
+    pub use crate::slice::{Slice, SliceMut, SliceRange};
 
-    pub use crate::ctx::Ctx;
+    pub use crate::backend::Backend;
+    pub use crate::ctx::{Ctx, CtxBuilder, FuncInfo, NamedHostFn, OptLevel};
+    pub use crate::error::LegoError;
     pub use crate::func::Call;
-    pub use crate::func::IntoHostFn;
+    pub use crate::func::{HostFnPtr, IntoHostFn};
     pub use crate::func::Param;
     pub use crate::refs::JitSafe;
+    pub use crate::trap::{trap, Trap};
 
     pub use crate::arithmetic::*;
-    pub use crate::iterator::{IntoJiter, JIterator};
+    pub use crate::iterator::{IntoJiter, ItemValue, JIterator};
+    pub use crate::option::StagedOption;
     pub use crate::func::CompiledFunc;
 
     pub use lego_macros::lego;
@@ -9,12 +9,29 @@ macro_rules! map_ident {
 
 #[macro_export]
 #[doc(hidden)]
-macro_rules! for_all_primitives {
+macro_rules! for_all_integers {
     ($cb:ident) => {
         $crate::map_ident!($cb: i8, i16, i32, i64, u8, u16, u32, u64, usize, isize);
     };
 }
 
+#[macro_export]
+#[doc(hidden)]
+macro_rules! for_all_floats {
+    ($cb:ident) => {
+        $crate::map_ident!($cb: f32, f64);
+    };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! for_all_primitives {
+    ($cb:ident) => {
+        $crate::for_all_integers!($cb);
+        $crate::for_all_floats!($cb);
+    };
+}
+
 #[macro_export]
 #[doc(hidden)]
 macro_rules! for_all_tuples {
@@ -0,0 +1,66 @@
+use cranelift::prelude::{Block, Value};
+
+use crate::control_flow::select::select;
+use crate::control_flow::BlockRet;
+use crate::func::FnCtx;
+use crate::val::Val;
+
+/// A staged `Option<T>`: whether there is a value is only known when the function runs.
+///
+/// The value is always present in the generated code, and unspecified when there is none.
+#[derive(Clone, Copy)]
+pub struct StagedOption<T> {
+    is_some: Val<bool>,
+    value: T,
+}
+
+impl<T> StagedOption<T> {
+    pub(crate) fn new(is_some: Val<bool>, value: T) -> Self {
+        Self { is_some, value }
+    }
+
+    pub fn is_some(&self) -> Val<bool> {
+        self.is_some
+    }
+
+    pub fn is_none(&self) -> Val<bool> {
+        !self.is_some
+    }
+
+    /// The value, or `default` if there is none.
+    pub fn unwrap_or(self, default: T) -> T
+    where
+        T: BlockRet,
+    {
+        select(self.is_some, self.value, default)
+    }
+
+    /// The value, without checking that there is one.
+    ///
+    /// # Safety
+    /// There must be a value when the function runs, the returned value is unspecified
+    /// otherwise.
+    pub unsafe fn unwrap_unchecked(self) -> T {
+        self.value
+    }
+}
+
+impl<T: BlockRet> BlockRet for StagedOption<T> {
+    fn push_param_ty(ctx: &mut FnCtx, block: Block) {
+        <(Val<bool>, T)>::push_param_ty(ctx, block);
+    }
+
+    fn read_from_ret(block_params: &mut impl Iterator<Item = Value>) -> Self {
+        let (is_some, value) = <(Val<bool>, T)>::read_from_ret(block_params);
+        Self { is_some, value }
+    }
+
+    fn to_block_values(&self, out: &mut Vec<Value>) {
+        self.is_some.to_block_values(out);
+        self.value.to_block_values(out);
+    }
+
+    fn null(ctx: &mut FnCtx, out: &mut Vec<Value>) {
+        <(Val<bool>, T)>::null(ctx, out);
+    }
+}
@@ -1,9 +1,20 @@
 use cranelift::prelude::types::*;
-use cranelift::prelude::Type;
+use cranelift::prelude::{InstBuilder as _, Type, Value};
+
+use crate::for_all_integers;
+use crate::func::FnCtx;
 
 pub trait Primitive {
     fn to_i64(self) -> i64;
     fn ty() -> Type;
+
+    /// Emit a constant holding `self` in the current function.
+    fn materialize(self, ctx: &mut FnCtx) -> Value
+    where
+        Self: Sized,
+    {
+        ctx.builder().ins().iconst(Self::ty(), self.to_i64())
+    }
 }
 
 impl<T: Sized> Primitive for &T {
@@ -89,3 +100,40 @@ primitive_jit_ty! {
     usize,
     isize,
 }
+
+macro_rules! primitive_float_ty {
+    ($($src:ident => $ty:ident, $konst:ident $(,)?)*) => {
+        $(
+            impl Primitive for $src {
+                fn to_i64(self) -> i64 {
+                    // floats are never materialized through iconst, this is only the bit pattern
+                    self.to_bits() as i64
+                }
+
+                fn ty() -> Type {
+                    $ty
+                }
+
+                fn materialize(self, ctx: &mut FnCtx) -> Value {
+                    ctx.builder().ins().$konst(self)
+                }
+            }
+        )*
+    };
+}
+
+primitive_float_ty! {
+    f32 => F32, f32const,
+    f64 => F64, f64const,
+}
+
+/// Integer primitives, that can be switched on.
+pub trait Integer: Primitive + Copy + PartialEq {}
+
+macro_rules! impl_integer {
+    ($ty:ident) => {
+        impl Integer for $ty {}
+    };
+}
+
+for_all_integers!(impl_integer);
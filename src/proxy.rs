@@ -146,9 +146,29 @@ pub struct Proxy<T> {
     pub ptr: PtrMut<T>,
 }
 
-fn drop_ptr<T>(p: *mut T) {
+/// Call `f`, a `fn(*mut u8, *mut u8)`, with `a` and `b`. The proxies call their typed functions
+/// through it: it isn't generic, so the host closure calling it is `'static` whatever `T` is.
+fn call_erased(f: *mut u8, a: *mut u8, b: *mut u8) {
+    let f = unsafe { std::mem::transmute::<*mut u8, fn(*mut u8, *mut u8)>(f) };
+    f(a, b)
+}
+
+fn ctor_ptr<T>(slot: *mut u8, ctor: *mut u8) {
+    unsafe {
+        let ctor = std::mem::transmute::<*mut u8, fn() -> T>(ctor);
+        slot.cast::<T>().write(ctor());
+    }
+}
+
+fn drop_ptr<T>(p: *mut u8, _: *mut u8) {
     unsafe {
-        std::ptr::read(p);
+        std::ptr::read(p.cast::<T>());
+    }
+}
+
+impl<T> PtrMut<T> {
+    fn erased(&self) -> Val<*mut u8> {
+        Val::from_value(self.addr.value())
     }
 }
 
@@ -158,14 +178,10 @@ impl<T> Proxy<T> {
     }
 
     pub fn ctor(ctor: fn() -> T) -> Self {
-        let tramp = (|slot: *mut T, ctor: *mut u8| unsafe {
-            let ctor = std::mem::transmute::<*mut u8, fn() -> T>(ctor);
-            slot.write(ctor());
-        })
-        .into_host_fn();
+        let tramp = call_erased.into_host_fn();
 
-        let ctor = ctor as *mut u8;
-        let val = Val::new(ctor);
+        let f = Val::new(ctor_ptr::<T> as fn(*mut u8, *mut u8) as *mut u8);
+        let ctor = Val::new(ctor as *mut u8);
 
         let addr = with_ctx(|ctx| {
             let data = StackSlotData::new(
@@ -179,10 +195,10 @@ impl<T> Proxy<T> {
             addr
         });
 
-        let mut ptr = PtrMut {
+        let ptr = PtrMut {
             addr: Val::from_value(addr),
         };
-        tramp.call((&mut ptr, val));
+        tramp.call((f, ptr.erased(), ctor));
 
         Self { ptr }
     }
@@ -194,7 +210,9 @@ impl<T> Proxy<T> {
 
 impl<T> Drop for Proxy<T> {
     fn drop(&mut self) {
-        let f = drop_ptr::<T>.into_host_fn();
-        f.call(&mut self.ptr)
+        let f = call_erased.into_host_fn();
+        let drop = Val::new(drop_ptr::<T> as fn(*mut u8, *mut u8) as *mut u8);
+        let unused = Val::new(std::ptr::null_mut::<u8>());
+        f.call((drop, self.ptr.erased(), unused))
     }
 }
@@ -1,10 +1,14 @@
 use std::marker::PhantomData;
+use std::ops::{Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+
+use cranelift::prelude::{InstBuilder as _, TrapCode};
 
 use crate::cmp::Compare;
-use crate::func::{FnCtx, Param};
+use crate::func::{with_ctx, FnCtx, Param};
 use crate::iterator::JIterator;
 use crate::prelude::IntoJiter;
-use crate::proxy::Ref;
+use crate::primitive::Primitive;
+use crate::proxy::{Ref, RefMut};
 use crate::val::{AsVal, Val};
 use crate::var::Var;
 
@@ -25,14 +29,174 @@ impl<'a, T> Slice<'a, T> {
         self.len
     }
 
+    /// The element at `idx`. The function traps with [`Trap::OutOfBounds`](crate::prelude::Trap)
+    /// if `idx` is out of bounds.
     pub fn get(&self, idx: impl AsVal<Ty = usize>) -> Ref<'a, T> {
-        // TODO bound checks?
-        let offset = Val::<usize>::from(self.base) + idx.value() * size_of::<T>();
-        let ptr = unsafe { offset.transmute() };
-        Ref::new(ptr)
+        let idx = idx.value();
+        bounds_check(idx, self.len);
+        unsafe { self.get_unchecked(idx) }
+    }
+
+    /// The element at `idx`, without bounds check.
+    ///
+    /// # Safety
+    /// `idx` must be less than the length of the slice when the function runs.
+    pub unsafe fn get_unchecked(&self, idx: impl AsVal<Ty = usize>) -> Ref<'a, T> {
+        Ref::new(elem_addr::<T>(self.base.into(), idx).transmute())
+    }
+
+    /// The first element, trapping if the slice is empty.
+    pub fn first(&self) -> Ref<'a, T> {
+        self.get(0usize)
+    }
+
+    /// The last element, trapping if the slice is empty.
+    pub fn last(&self) -> Ref<'a, T> {
+        // wraps around for an empty slice, and fails the bounds check
+        self.get(self.len - 1usize)
+    }
+
+    /// The sub-slice covering `range`, e.g. `s.slice(start..)` or `s.slice(..Val::new(4usize))`.
+    /// The function traps with [`Trap::OutOfBounds`](crate::prelude::Trap) if the range is
+    /// decreasing or ends past the end of the slice.
+    pub fn slice(&self, range: impl SliceRange) -> Slice<'a, T> {
+        let (start, end) = range.bounds(self.len);
+        check_in_bounds(start.le(end));
+        check_in_bounds(end.le(self.len));
+        self.view(start, end - start)
+    }
+
+    /// Split the slice in `[0, mid)` and `[mid, len)`, trapping if `mid` is greater than the
+    /// length.
+    pub fn split_at(&self, mid: impl AsVal<Ty = usize>) -> (Slice<'a, T>, Slice<'a, T>) {
+        let mid = mid.value();
+        check_in_bounds(mid.le(self.len));
+        (self.view(0usize, mid), self.view(mid, self.len - mid))
+    }
+
+    /// Views on `size` elements at a time, the last one being shorter if `size` doesn't divide
+    /// the length. The function traps with [`Trap::OutOfBounds`](crate::prelude::Trap) if
+    /// `size` is zero.
+    pub fn chunks(&self, size: impl AsVal<Ty = usize>) -> Chunks<'a, T> {
+        Chunks {
+            offset: Var::new(0usize),
+            size: non_zero_size(size),
+            slice: *self,
+        }
+    }
+
+    /// Views on exactly `size` elements at a time, the remaining elements are skipped. The
+    /// function traps with [`Trap::OutOfBounds`](crate::prelude::Trap) if `size` is zero.
+    ///
+    /// See [`Slice::array_chunks`] for views of a constant length.
+    pub fn chunks_exact(&self, size: impl AsVal<Ty = usize>) -> ChunksExact<'a, T> {
+        ChunksExact {
+            offset: Var::new(0usize),
+            size: non_zero_size(size),
+            slice: *self,
+        }
+    }
+
+    /// Views on exactly `N` elements at a time, the remaining elements are skipped.
+    ///
+    /// The length of the views is a constant, so their elements are accessed without bounds
+    /// check, e.g. from a host loop over `0..N` that is unrolled in the generated code. Panics
+    /// if `N` is zero.
+    pub fn array_chunks<const N: usize>(&self) -> ArrayChunks<'a, T, N> {
+        assert_ne!(N, 0, "chunk size must be non-zero");
+        ArrayChunks {
+            offset: Var::new(0usize),
+            slice: *self,
+        }
+    }
+
+    /// Overlapping views on `size` contiguous elements. The function traps with
+    /// [`Trap::OutOfBounds`](crate::prelude::Trap) if `size` is zero.
+    pub fn windows(&self, size: impl AsVal<Ty = usize>) -> Windows<'a, T> {
+        Windows {
+            offset: Var::new(0usize),
+            size: non_zero_size(size),
+            slice: *self,
+        }
+    }
+
+    /// The view on `len` elements from `start`, without bounds check.
+    fn view(&self, start: impl AsVal<Ty = usize>, len: Val<usize>) -> Slice<'a, T> {
+        Slice {
+            // safety: the address of an element is a pointer to T
+            base: unsafe { elem_addr::<T>(self.base.into(), start).transmute() },
+            len,
+            _p: PhantomData,
+        }
+    }
+}
+
+/// Trap if `idx` is not less than `len`.
+fn bounds_check(idx: Val<usize>, len: Val<usize>) {
+    check_in_bounds(idx.lt(len));
+}
+
+fn check_in_bounds(in_bounds: Val<bool>) {
+    with_ctx(|ctx| {
+        ctx.builder()
+            .ins()
+            .trapz(in_bounds.value(), TrapCode::HEAP_OUT_OF_BOUNDS);
+    });
+}
+
+/// Chunks and windows of zero elements would never end.
+fn non_zero_size(size: impl AsVal<Ty = usize>) -> Val<usize> {
+    let size = size.value();
+    check_in_bounds(size.neq(0usize));
+    size
+}
+
+/// Ranges of indexes that can be passed to [`Slice::slice`].
+pub trait SliceRange {
+    /// The start and end of the range, for a slice of `len` elements.
+    fn bounds(self, len: Val<usize>) -> (Val<usize>, Val<usize>);
+}
+
+impl<I: AsVal<Ty = usize>> SliceRange for Range<I> {
+    fn bounds(self, _len: Val<usize>) -> (Val<usize>, Val<usize>) {
+        (self.start.value(), self.end.value())
+    }
+}
+
+impl<I: AsVal<Ty = usize>> SliceRange for RangeInclusive<I> {
+    fn bounds(self, _len: Val<usize>) -> (Val<usize>, Val<usize>) {
+        (self.start().value(), self.end().value() + 1usize)
+    }
+}
+
+impl<I: AsVal<Ty = usize>> SliceRange for RangeFrom<I> {
+    fn bounds(self, len: Val<usize>) -> (Val<usize>, Val<usize>) {
+        (self.start.value(), len)
+    }
+}
+
+impl<I: AsVal<Ty = usize>> SliceRange for RangeTo<I> {
+    fn bounds(self, _len: Val<usize>) -> (Val<usize>, Val<usize>) {
+        (Val::new(0usize), self.end.value())
     }
 }
 
+impl<I: AsVal<Ty = usize>> SliceRange for RangeToInclusive<I> {
+    fn bounds(self, _len: Val<usize>) -> (Val<usize>, Val<usize>) {
+        (Val::new(0usize), self.end.value() + 1usize)
+    }
+}
+
+impl SliceRange for RangeFull {
+    fn bounds(self, len: Val<usize>) -> (Val<usize>, Val<usize>) {
+        (Val::new(0usize), len)
+    }
+}
+
+fn elem_addr<T>(base: Val<usize>, idx: impl AsVal<Ty = usize>) -> Val<usize> {
+    base + idx.value() * size_of::<T>()
+}
+
 impl<'a, T> IntoJiter for Slice<'a, T> {
     type Iter = SliceIter<'a, T>;
     type Item = Ref<'a, T>;
@@ -64,6 +228,36 @@ pub struct SliceIter<'a, T> {
     slice: Slice<'a, T>,
 }
 
+impl<'a, T> SliceIter<'a, T> {
+    /// Iterate from the last element to the first.
+    pub fn rev(self) -> SliceRevIter<'a, T> {
+        SliceRevIter {
+            index: Var::new(self.slice.len),
+            slice: self.slice,
+        }
+    }
+}
+
+pub struct SliceRevIter<'a, T> {
+    /// one past the next element
+    index: Var<usize>,
+    slice: Slice<'a, T>,
+}
+
+impl<'a, T> JIterator for SliceRevIter<'a, T> {
+    type Item = Ref<'a, T>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let s = self.slice;
+        let ret = (self.index.value().neq(0usize), move || {
+            self.index -= 1usize;
+            // safety: the index was checked against zero, and started at the length
+            unsafe { s.get_unchecked(self.index) }
+        });
+        ret
+    }
+}
+
 impl<'a, T> JIterator for SliceIter<'a, T> {
     type Item = Ref<'a, T>;
 
@@ -71,7 +265,257 @@ impl<'a, T> JIterator for SliceIter<'a, T> {
         let s = self.slice;
         let index = self.index;
         let ret = (self.index.value().neq(self.slice.len()), move || {
-            let val = s.get(index);
+            // safety: the index was checked against the length
+            let val = unsafe { s.get_unchecked(index) };
+            self.index += 1usize;
+            val
+        });
+        ret
+    }
+}
+
+pub struct Chunks<'a, T> {
+    offset: Var<usize>,
+    size: Val<usize>,
+    slice: Slice<'a, T>,
+}
+
+impl<'a, T> JIterator for Chunks<'a, T> {
+    type Item = Slice<'a, T>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let offset = self.offset.value();
+        let ret = (offset.lt(self.slice.len), move || {
+            let len = self.size.min(self.slice.len - offset);
+            self.offset += len;
+            self.slice.view(offset, len)
+        });
+        ret
+    }
+}
+
+pub struct ChunksExact<'a, T> {
+    offset: Var<usize>,
+    size: Val<usize>,
+    slice: Slice<'a, T>,
+}
+
+impl<'a, T> JIterator for ChunksExact<'a, T> {
+    type Item = Slice<'a, T>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let offset = self.offset.value();
+        // the offset never goes past the length
+        let ret = (self.size.le(self.slice.len - offset), move || {
+            self.offset += self.size;
+            self.slice.view(offset, self.size)
+        });
+        ret
+    }
+}
+
+pub struct ArrayChunks<'a, T, const N: usize> {
+    offset: Var<usize>,
+    slice: Slice<'a, T>,
+}
+
+impl<'a, T, const N: usize> JIterator for ArrayChunks<'a, T, N> {
+    type Item = ArrayChunk<'a, T, N>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let offset = self.offset.value();
+        // the offset never goes past the length
+        let ret = ((self.slice.len - offset).ge(N), move || {
+            self.offset += N;
+            ArrayChunk {
+                base: self.slice.view(offset, Val::new(N)).base,
+                _p: PhantomData,
+            }
+        });
+        ret
+    }
+}
+
+/// A view on `N` elements, see [`Slice::array_chunks`].
+pub struct ArrayChunk<'a, T, const N: usize> {
+    pub base: Val<*const T>,
+    pub _p: PhantomData<&'a [T; N]>,
+}
+
+impl<T, const N: usize> Clone for ArrayChunk<'_, T, N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, const N: usize> Copy for ArrayChunk<'_, T, N> {}
+
+impl<'a, T, const N: usize> ArrayChunk<'a, T, N> {
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// The element at `idx`, panics if `idx` is out of bounds.
+    pub fn get(&self, idx: usize) -> Ref<'a, T> {
+        assert!(
+            idx < N,
+            "index {idx} is out of bounds of a chunk of {N} elements"
+        );
+        // safety: the index was checked against the length of the chunk
+        unsafe { self.as_slice().get_unchecked(idx) }
+    }
+
+    pub fn as_slice(&self) -> Slice<'a, T> {
+        Slice {
+            base: self.base,
+            len: Val::new(N),
+            _p: PhantomData,
+        }
+    }
+}
+
+pub struct Windows<'a, T> {
+    offset: Var<usize>,
+    size: Val<usize>,
+    slice: Slice<'a, T>,
+}
+
+impl<'a, T> JIterator for Windows<'a, T> {
+    type Item = Slice<'a, T>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let offset = self.offset.value();
+        // the offset never goes past the length
+        let ret = (self.size.le(self.slice.len - offset), move || {
+            self.offset += 1usize;
+            self.slice.view(offset, self.size)
+        });
+        ret
+    }
+}
+
+/// A mutable slice, the parameter type of `&mut [T]`.
+pub struct SliceMut<'a, T> {
+    pub base: Val<*mut T>,
+    pub len: Val<usize>,
+    pub _p: PhantomData<&'a mut [T]>,
+}
+
+impl<'a, T> SliceMut<'a, T> {
+    pub fn len(&self) -> Val<usize> {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> Slice<'_, T> {
+        Slice {
+            base: self.base.into(),
+            len: self.len,
+            _p: PhantomData,
+        }
+    }
+
+    /// The element at `idx`, see [`Slice::get`].
+    pub fn get(&self, idx: impl AsVal<Ty = usize>) -> Ref<'_, T> {
+        self.as_slice().get(idx)
+    }
+
+    /// The element at `idx`, for writing. The function traps with
+    /// [`Trap::OutOfBounds`](crate::prelude::Trap) if `idx` is out of bounds.
+    pub fn get_mut(&mut self, idx: impl AsVal<Ty = usize>) -> RefMut<'_, T> {
+        let idx = idx.value();
+        bounds_check(idx, self.len);
+        unsafe { self.get_unchecked_mut(idx) }
+    }
+
+    /// The element at `idx`, for writing, without bounds check.
+    ///
+    /// # Safety
+    /// `idx` must be less than the length of the slice when the function runs.
+    pub unsafe fn get_unchecked_mut(&mut self, idx: impl AsVal<Ty = usize>) -> RefMut<'_, T> {
+        RefMut::new(elem_addr::<T>(self.base.into(), idx).transmute())
+    }
+
+    /// Write `val` at `idx`, trapping if `idx` is out of bounds.
+    pub fn set(&mut self, idx: impl AsVal<Ty = usize>, val: impl AsVal<Ty = T>) {
+        self.get_mut(idx).put(val);
+    }
+
+    /// Swap the elements at `a` and `b`, trapping if either is out of bounds.
+    pub fn swap(&mut self, a: impl AsVal<Ty = usize>, b: impl AsVal<Ty = usize>)
+    where
+        T: Primitive,
+    {
+        let a = a.value();
+        let b = b.value();
+        let val_a = self.get(a).get();
+        let val_b = self.get(b).get();
+        // safety: both indexes were checked by `get`
+        unsafe {
+            self.get_unchecked_mut(a).put(val_b);
+            self.get_unchecked_mut(b).put(val_a);
+        }
+    }
+
+    /// Write `val` to every element of the slice.
+    pub fn fill(&mut self, val: impl AsVal<Ty = T>) {
+        let val = val.value();
+        self.iter_mut().for_each(|mut elem| elem.put(val));
+    }
+
+    pub fn iter_mut(&mut self) -> SliceIterMut<'_, T> {
+        SliceMut {
+            base: self.base,
+            len: self.len,
+            _p: PhantomData,
+        }
+        .into_jiter()
+    }
+}
+
+impl<'a, T> IntoJiter for SliceMut<'a, T> {
+    type Iter = SliceIterMut<'a, T>;
+    type Item = RefMut<'a, T>;
+
+    fn into_jiter(self) -> Self::Iter {
+        SliceIterMut {
+            index: Var::new(0usize),
+            slice: self,
+        }
+    }
+}
+
+impl<'a, T> Param for &'a mut [T] {
+    type Ty = SliceMut<'a, T>;
+
+    fn initialize_param_at(ctx: &mut FnCtx, idxs: &mut impl Iterator<Item = usize>) -> Self::Ty {
+        let len = usize::initialize_param_at(ctx, idxs);
+        let base = <*mut T>::initialize_param_at(ctx, idxs);
+        SliceMut {
+            base: base.addr,
+            len: len.as_val(ctx),
+            _p: PhantomData,
+        }
+    }
+}
+
+pub struct SliceIterMut<'a, T> {
+    index: Var<usize>,
+    slice: SliceMut<'a, T>,
+}
+
+impl<'a, T> JIterator for SliceIterMut<'a, T> {
+    type Item = RefMut<'a, T>;
+
+    fn next(&mut self) -> (Val<bool>, impl FnOnce() -> Self::Item) {
+        let base = self.slice.base;
+        let index = self.index;
+        let ret = (self.index.value().neq(self.slice.len()), move || {
+            // the index was checked against the length
+            let val = RefMut::new(unsafe { elem_addr::<T>(base.into(), index).transmute() });
             self.index += 1usize;
             val
         });
@@ -0,0 +1,471 @@
+use std::fmt;
+
+use cranelift::prelude::{InstBuilder, TrapCode};
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use crate::ffi::{RawArgs, RawRet};
+use crate::func::with_ctx;
+
+/// Why the generated code trapped, see [`Function::call_checked`](crate::ffi::Function).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// An integer division or remainder by zero.
+    DivisionByZero,
+    /// An integer overflow, e.g. dividing `i64::MIN` by `-1`.
+    IntegerOverflow,
+    /// An access out of the bounds of a slice.
+    OutOfBounds,
+    /// A float couldn't be converted to an integer.
+    BadConversion,
+    /// A trap emitted with [`trap`], with its code.
+    User(u8),
+    /// Another trap reserved by cranelift, e.g. a stack overflow.
+    Other(TrapCode),
+}
+
+impl Trap {
+    pub(crate) fn from_code(code: TrapCode) -> Self {
+        match code {
+            TrapCode::INTEGER_DIVISION_BY_ZERO => Trap::DivisionByZero,
+            TrapCode::INTEGER_OVERFLOW => Trap::IntegerOverflow,
+            TrapCode::HEAP_OUT_OF_BOUNDS => Trap::OutOfBounds,
+            TrapCode::BAD_CONVERSION_TO_INTEGER => Trap::BadConversion,
+            code if TrapCode::user(code.as_raw().get()) == Some(code) => {
+                Trap::User(code.as_raw().get())
+            }
+            code => Trap::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::DivisionByZero => write!(f, "integer division by zero"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::OutOfBounds => write!(f, "out of bounds access"),
+            Trap::BadConversion => write!(f, "bad conversion to integer"),
+            Trap::User(code) => write!(f, "user trap {code}"),
+            Trap::Other(code) => write!(f, "trap {code}"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// Trap unconditionally, with a user `code` between 1 and 250.
+///
+/// The code following the trap is unreachable.
+pub fn trap(code: u8) {
+    let code = TrapCode::user(code).expect("user trap codes are between 1 and 250");
+    with_ctx(|ctx| {
+        let [dead_block] = ctx.create_blocks();
+        let b = ctx.builder();
+        b.ins().trap(code);
+        // the trap is a terminator, keep emitting in a block without predecessors
+        b.switch_to_block(dead_block);
+        b.seal_block(dead_block);
+    })
+}
+
+/// Call the generated function `f` with `args`, returning the trap it raised, if any. `sites`
+/// are the addresses of the trapping instructions of the generated code, sorted.
+///
+/// # Safety
+/// `f` must point to generated code taking `args`.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) unsafe fn call_checked(
+    sites: &[(usize, TrapCode)],
+    f: *const u8,
+    args: &RawArgs,
+) -> Result<RawRet, Trap> {
+    signals::call_checked(sites, f, args)
+}
+
+/// Run a host closure called by the generated code. Traps hit in there aren't caught by the
+/// checked call in progress, as it can only skip the frames of the generated code.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) fn in_host_closure<R>(f: impl FnOnce() -> R) -> R {
+    signals::in_host_closure(f)
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub(crate) fn in_host_closure<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Traps raise `SIGILL` or `SIGFPE`. A checked call goes through `lego_call_checked`, which
+/// calls the generated code directly: only frames of the generated code, which own nothing,
+/// lie between them. While the call is in progress, the signal handler looks up the faulting
+/// instruction in the trap sites of the call. If it is one, the handler makes the thread resume
+/// in `lego_trap_landing`, which returns from `lego_call_checked` a second time. Other signals
+/// are forwarded to the previous handler.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod signals {
+    use std::cell::Cell;
+    use std::mem::MaybeUninit;
+    use std::ptr;
+    use std::sync::OnceLock;
+
+    use cranelift::prelude::TrapCode;
+    use libc::{c_int, c_void, sigaction, siginfo_t};
+
+    use super::Trap;
+    use crate::ffi::{RawArgs, RawRet};
+
+    /// The callee-saved registers and stack pointer of the caller of `lego_call_checked`, and
+    /// the address it returns to.
+    #[repr(C)]
+    struct JmpBuf([u64; 24]);
+
+    extern "C" {
+        /// Save the state of the caller in `buf`, and call the generated function `f` with `args`,
+        /// writing what it returned to `ret`. Returns 0 once `f` returns, or 1 if the thread was
+        /// resumed in `lego_trap_landing` with `buf`.
+        fn lego_call_checked(
+            buf: *mut JmpBuf,
+            f: *const u8,
+            args: *const RawArgs,
+            ret: *mut RawRet,
+        ) -> c_int;
+
+        /// Restore the state saved in its first argument, and return 1 from `lego_call_checked`.
+        /// Only jumped to from a signal handler, never called.
+        fn lego_trap_landing();
+    }
+
+    // the args: the integers at offset 0, the first 6 in registers and the others on the stack,
+    // and the floats at offset 128, all in registers.
+    #[cfg(target_arch = "x86_64")]
+    std::arch::global_asm!(
+        ".text",
+        ".p2align 4",
+        ".globl lego_call_checked",
+        ".hidden lego_call_checked",
+        ".type lego_call_checked, @function",
+        "lego_call_checked:",
+        "mov [rdi], rbx",
+        "mov [rdi + 8], rbp",
+        "mov [rdi + 16], r12",
+        "mov [rdi + 24], r13",
+        "mov [rdi + 32], r14",
+        "mov [rdi + 40], r15",
+        // the stack pointer once returned, and the return address
+        "lea rax, [rsp + 8]",
+        "mov [rdi + 48], rax",
+        "mov rax, [rsp]",
+        "mov [rdi + 56], rax",
+        // 80 bytes of stack arguments, and `ret`, keeping the stack aligned
+        "push rbp",
+        "mov rbp, rsp",
+        "sub rsp, 96",
+        "mov [rbp - 8], rcx",
+        "mov r11, rsi",
+        "mov r10, rdx",
+        "mov rax, [r10 + 48]",
+        "mov [rsp], rax",
+        "mov rax, [r10 + 56]",
+        "mov [rsp + 8], rax",
+        "mov rax, [r10 + 64]",
+        "mov [rsp + 16], rax",
+        "mov rax, [r10 + 72]",
+        "mov [rsp + 24], rax",
+        "mov rax, [r10 + 80]",
+        "mov [rsp + 32], rax",
+        "mov rax, [r10 + 88]",
+        "mov [rsp + 40], rax",
+        "mov rax, [r10 + 96]",
+        "mov [rsp + 48], rax",
+        "mov rax, [r10 + 104]",
+        "mov [rsp + 56], rax",
+        "mov rax, [r10 + 112]",
+        "mov [rsp + 64], rax",
+        "mov rax, [r10 + 120]",
+        "mov [rsp + 72], rax",
+        "movsd xmm0, qword ptr [r10 + 128]",
+        "movsd xmm1, qword ptr [r10 + 136]",
+        "movsd xmm2, qword ptr [r10 + 144]",
+        "movsd xmm3, qword ptr [r10 + 152]",
+        "movsd xmm4, qword ptr [r10 + 160]",
+        "movsd xmm5, qword ptr [r10 + 168]",
+        "movsd xmm6, qword ptr [r10 + 176]",
+        "movsd xmm7, qword ptr [r10 + 184]",
+        "mov rdi, [r10]",
+        "mov rsi, [r10 + 8]",
+        "mov rdx, [r10 + 16]",
+        "mov rcx, [r10 + 24]",
+        "mov r8, [r10 + 32]",
+        "mov r9, [r10 + 40]",
+        "call r11",
+        "mov rcx, [rbp - 8]",
+        "mov [rcx], rax",
+        "movsd qword ptr [rcx + 8], xmm0",
+        "mov rsp, rbp",
+        "pop rbp",
+        "xor eax, eax",
+        "ret",
+        ".size lego_call_checked, . - lego_call_checked",
+        "",
+        ".p2align 4",
+        ".globl lego_trap_landing",
+        ".hidden lego_trap_landing",
+        ".type lego_trap_landing, @function",
+        "lego_trap_landing:",
+        "mov rbx, [rdi]",
+        "mov rbp, [rdi + 8]",
+        "mov r12, [rdi + 16]",
+        "mov r13, [rdi + 24]",
+        "mov r14, [rdi + 32]",
+        "mov r15, [rdi + 40]",
+        "mov rsp, [rdi + 48]",
+        "mov eax, 1",
+        "jmp qword ptr [rdi + 56]",
+        ".size lego_trap_landing, . - lego_trap_landing",
+    );
+
+    // the args: the integers at offset 0, the first 8 in registers and the others on the stack,
+    // and the floats at offset 128, all in registers.
+    #[cfg(target_arch = "aarch64")]
+    std::arch::global_asm!(
+        ".text",
+        ".p2align 2",
+        ".globl lego_call_checked",
+        ".hidden lego_call_checked",
+        ".type lego_call_checked, %function",
+        "lego_call_checked:",
+        "stp x19, x20, [x0]",
+        "stp x21, x22, [x0, #16]",
+        "stp x23, x24, [x0, #32]",
+        "stp x25, x26, [x0, #48]",
+        "stp x27, x28, [x0, #64]",
+        // the frame pointer, and the return address
+        "stp x29, x30, [x0, #80]",
+        "mov x9, sp",
+        "str x9, [x0, #96]",
+        "stp d8, d9, [x0, #104]",
+        "stp d10, d11, [x0, #120]",
+        "stp d12, d13, [x0, #136]",
+        "stp d14, d15, [x0, #152]",
+        // 64 bytes of stack arguments, and `ret`, keeping the stack aligned
+        "stp x29, x30, [sp, #-16]!",
+        "mov x29, sp",
+        "sub sp, sp, #80",
+        "str x3, [sp, #64]",
+        "mov x9, x1",
+        "mov x10, x2",
+        "ldp x11, x12, [x10, #64]",
+        "stp x11, x12, [sp]",
+        "ldp x11, x12, [x10, #80]",
+        "stp x11, x12, [sp, #16]",
+        "ldp x11, x12, [x10, #96]",
+        "stp x11, x12, [sp, #32]",
+        "ldp x11, x12, [x10, #112]",
+        "stp x11, x12, [sp, #48]",
+        "ldp d0, d1, [x10, #128]",
+        "ldp d2, d3, [x10, #144]",
+        "ldp d4, d5, [x10, #160]",
+        "ldp d6, d7, [x10, #176]",
+        "ldp x0, x1, [x10]",
+        "ldp x2, x3, [x10, #16]",
+        "ldp x4, x5, [x10, #32]",
+        "ldp x6, x7, [x10, #48]",
+        "blr x9",
+        "ldr x3, [sp, #64]",
+        "str x0, [x3]",
+        "str d0, [x3, #8]",
+        "mov sp, x29",
+        "ldp x29, x30, [sp], #16",
+        "mov x0, #0",
+        "ret",
+        ".size lego_call_checked, . - lego_call_checked",
+        "",
+        ".p2align 2",
+        ".globl lego_trap_landing",
+        ".hidden lego_trap_landing",
+        ".type lego_trap_landing, %function",
+        "lego_trap_landing:",
+        "ldp x19, x20, [x0]",
+        "ldp x21, x22, [x0, #16]",
+        "ldp x23, x24, [x0, #32]",
+        "ldp x25, x26, [x0, #48]",
+        "ldp x27, x28, [x0, #64]",
+        "ldp x29, x30, [x0, #80]",
+        "ldr x9, [x0, #96]",
+        "mov sp, x9",
+        "ldp d8, d9, [x0, #104]",
+        "ldp d10, d11, [x0, #120]",
+        "ldp d12, d13, [x0, #136]",
+        "ldp d14, d15, [x0, #152]",
+        "mov x0, #1",
+        "ret",
+        ".size lego_trap_landing, . - lego_trap_landing",
+    );
+
+    /// A checked call in progress on the current thread.
+    struct TrapFrame {
+        jmp_buf: JmpBuf,
+        sites: *const [(usize, TrapCode)],
+        trap: Cell<Option<TrapCode>>,
+    }
+
+    thread_local! {
+        static ACTIVE: Cell<*mut TrapFrame> = const { Cell::new(ptr::null_mut()) };
+    }
+
+    const SIGNALS: [c_int; 2] = [libc::SIGILL, libc::SIGFPE];
+
+    /// The handlers installed before ours, for the signals in `SIGNALS`. Ours are installed
+    /// once, by the first checked call, and stay installed.
+    static PREV_HANDLERS: OnceLock<[sigaction; 2]> = OnceLock::new();
+
+    /// Restores the previous frame, including when the call panics.
+    struct ActiveGuard(*mut TrapFrame);
+
+    impl ActiveGuard {
+        fn enter(frame: *mut TrapFrame) -> Self {
+            PREV_HANDLERS.get_or_init(install_handlers);
+            Self(ACTIVE.with(|active| active.replace(frame)))
+        }
+    }
+
+    impl Drop for ActiveGuard {
+        fn drop(&mut self) {
+            ACTIVE.with(|active| active.set(self.0));
+        }
+    }
+
+    pub(super) unsafe fn call_checked(
+        sites: &[(usize, TrapCode)],
+        f: *const u8,
+        args: &RawArgs,
+    ) -> Result<RawRet, Trap> {
+        let mut frame = TrapFrame {
+            jmp_buf: JmpBuf([0; 24]),
+            sites,
+            trap: Cell::new(None),
+        };
+        let guard = ActiveGuard::enter(&mut frame);
+        let mut ret = RawRet::default();
+        let trapped = lego_call_checked(&mut frame.jmp_buf, f, args, &mut ret) != 0;
+        drop(guard);
+
+        if trapped {
+            Err(Trap::from_code(
+                frame.trap.get().expect("trap without a code"),
+            ))
+        } else {
+            Ok(ret)
+        }
+    }
+
+    pub(super) fn in_host_closure<R>(f: impl FnOnce() -> R) -> R {
+        let _guard = ActiveGuard(ACTIVE.with(|active| active.replace(ptr::null_mut())));
+        f()
+    }
+
+    fn install_handlers() -> [sigaction; 2] {
+        SIGNALS.map(|signal| unsafe {
+            let mut action: sigaction = MaybeUninit::zeroed().assume_init();
+            let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = handler;
+            action.sa_sigaction = handler as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut prev: sigaction = MaybeUninit::zeroed().assume_init();
+            assert_eq!(libc::sigaction(signal, &action, &mut prev), 0);
+            prev
+        })
+    }
+
+    /// Make the interrupted thread resume in `lego_trap_landing`, with `jmp_buf` as argument.
+    unsafe fn resume_in_landing(ucontext: *mut c_void, jmp_buf: *mut JmpBuf) {
+        let ucontext = &mut *(ucontext as *mut libc::ucontext_t);
+        let landing: unsafe extern "C" fn() = lego_trap_landing;
+        #[cfg(target_arch = "x86_64")]
+        {
+            let gregs = &mut ucontext.uc_mcontext.gregs;
+            gregs[libc::REG_RIP as usize] = landing as usize as i64;
+            gregs[libc::REG_RDI as usize] = jmp_buf as i64;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            ucontext.uc_mcontext.pc = landing as usize as u64;
+            ucontext.uc_mcontext.regs[0] = jmp_buf as u64;
+        }
+    }
+
+    unsafe fn faulting_pc(ucontext: *mut c_void) -> usize {
+        let ucontext = &*(ucontext as *const libc::ucontext_t);
+        #[cfg(target_arch = "x86_64")]
+        {
+            ucontext.uc_mcontext.gregs[libc::REG_RIP as usize] as usize
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            ucontext.uc_mcontext.pc as usize
+        }
+    }
+
+    extern "C" fn handler(signal: c_int, info: *mut siginfo_t, ucontext: *mut c_void) {
+        unsafe {
+            let frame = ACTIVE.with(|active| active.get());
+            if let Some(frame) = frame.as_mut() {
+                let pc = faulting_pc(ucontext);
+                let sites = &*frame.sites;
+                if let Ok(idx) = sites.binary_search_by_key(&pc, |(site, _)| *site) {
+                    frame.trap.set(Some(sites[idx].1));
+                    // returning from the handler restores the signal mask, and jumps there
+                    resume_in_landing(ucontext, &mut frame.jmp_buf);
+                    return;
+                }
+            }
+
+            // not a trap of the generated code
+            let Some(prev) = PREV_HANDLERS
+                .get()
+                .and_then(|prevs| SIGNALS.iter().position(|s| *s == signal).map(|i| &prevs[i]))
+            else {
+                return;
+            };
+            // a positive code means the signal was raised by the faulting instruction
+            let fault = (*info).si_code > 0;
+            match prev.sa_sigaction {
+                // faults can't be ignored
+                libc::SIG_IGN if !fault => {}
+                libc::SIG_DFL | libc::SIG_IGN => {
+                    // the default action terminates the process: restore it, and let the
+                    // instruction fault again, or the signal be delivered again once we return
+                    libc::signal(signal, libc::SIG_DFL);
+                    if !fault {
+                        libc::raise(signal);
+                    }
+                }
+                action if prev.sa_flags & libc::SA_SIGINFO != 0 => {
+                    let prev = std::mem::transmute::<
+                        usize,
+                        extern "C" fn(c_int, *mut siginfo_t, *mut c_void),
+                    >(action);
+                    prev(signal, info, ucontext);
+                }
+                action => {
+                    let prev = std::mem::transmute::<usize, extern "C" fn(c_int)>(action);
+                    prev(signal);
+                }
+            }
+        }
+    }
+}
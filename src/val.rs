@@ -1,6 +1,5 @@
 use std::marker::PhantomData;
 
-use cranelift::prelude::InstBuilder as _;
 use cranelift::prelude::Value;
 
 use crate::func::with_ctx;
@@ -26,7 +25,7 @@ impl<T> Val<T> {
         T: Primitive,
     {
         with_ctx(|ctx| {
-            let val = ctx.builder().ins().iconst(T::ty(), val.to_i64());
+            let val = val.materialize(ctx);
             Val::from_value(val)
         })
     }
@@ -90,7 +89,7 @@ macro_rules! impl_into_var_primitive {
             impl AsVal for $prim {
                 type Ty = $prim;
                 fn as_val(&self, ctx: &mut FnCtx) -> Val<Self::Ty> {
-                    let value = ctx.builder.ins().iconst(Self::ty(), *self as i64);
+                    let value = (*self).materialize(ctx);
                     Val::from_value(value)
                 }
             }
@@ -125,6 +124,7 @@ impl<T> AsVal for &PtrMut<T> {
 impl_into_var_primitive! {
     u8, u16, u32, u64, usize,
     i8, i16, i32, i64, isize,
+    f32, f64,
     bool,
 }
 
@@ -48,7 +48,7 @@ impl<T> Var<T> {
         })
     }
 
-    fn assign_ctx(&mut self, ctx: &mut FnCtx, val: impl AsVal<Ty = T>) {
+    pub(crate) fn assign_ctx(&mut self, ctx: &mut FnCtx, val: impl AsVal<Ty = T>) {
         let value = val.as_val(ctx);
         ctx.builder().def_var(self.variable(), value.value());
     }
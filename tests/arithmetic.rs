@@ -0,0 +1,111 @@
+use lego::ffi::Function;
+use lego::prelude::*;
+
+#[test]
+fn not_bool() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u8, u8>(|x| {
+        let is_zero = x.value().eq(Val::new(0u8));
+        (!is_zero).then(|| (Val::new(1u8), || Val::new(0u8)))
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(0), 0);
+    assert_eq!(f.call(3), 1);
+
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u8, bool>(|x| !!x.value().eq(Val::new(0u8)));
+    let f = ctx.get_compiled_function(f);
+    assert!(f.call(0));
+    assert!(!f.call(3));
+}
+
+#[test]
+fn float_arithmetic() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<(f64, f64), f64>(|(x, y)| {
+        let mut acc = Var::new(x);
+        acc *= y;
+        acc += 1.5f64;
+        acc -= 0.5f64;
+        acc /= 2.0f64;
+        acc.value() - x
+    });
+    let g = ctx.func::<(f32, f32), (f32, bool, bool)>(|(x, y)| {
+        let sum = x.value() + y.value() * 2.0f32;
+        (sum / 4.0f32, x.lt(y), x.value().eq(y))
+    });
+    let f = ctx.get_compiled_function(f);
+    let g = ctx.get_compiled_function(g);
+    assert_eq!(f.call((3.0, 2.0)), (3.0 * 2.0 + 1.5 - 0.5) / 2.0 - 3.0);
+    assert_eq!(f.call((-1.0, 0.25)), (-0.25 + 1.0) / 2.0 + 1.0);
+    assert!(f.call((f64::NAN, 1.0)).is_nan());
+    assert_eq!(g.call((1.0, 2.5)), (1.5, true, false));
+    assert_eq!(g.call((2.0, 2.0)), (1.5, false, true));
+    // comparisons with NaN are all false
+    let (sum, lt, eq) = g.call((f32::NAN, 1.0));
+    assert!(sum.is_nan() && !lt && !eq);
+}
+
+#[test]
+fn float_min_max() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<(f64, f64), f64>(|(x, y)| {
+        let v = Var::new(x);
+        v.min(y) + v.max(y) * 10.0f64
+    });
+    let g = ctx.func::<(f32, f32), (f32, f32)>(|(x, y)| (x.value().min(y), x.value().max(y)));
+    let f = ctx.get_compiled_function(f);
+    let g = ctx.get_compiled_function(g);
+    assert_eq!(f.call((1.0, 2.0)), 21.0);
+    assert_eq!(f.call((2.0, -1.0)), 19.0);
+    // like `f64::min` and `f64::max`, NaN is only returned if both are NaN
+    assert_eq!(f.call((f64::NAN, 2.0)), 22.0);
+    assert_eq!(f.call((3.0, f64::NAN)), 33.0);
+    assert!(f.call((f64::NAN, f64::NAN)).is_nan());
+    assert_eq!(g.call((f32::NAN, -1.5)), (-1.5, -1.5));
+    assert_eq!(g.call((0.5, 0.25)), (0.25, 0.5));
+}
+
+#[test]
+fn signed_unsigned_ordering() {
+    let mut ctx = Ctx::builder().build();
+    // the same bits compare differently depending on the signedness of the type
+    let signed = ctx.func::<(i32, i32), (bool, bool, bool, bool)>(|(x, y)| {
+        (x.lt(y), x.le(y), x.gt(y), x.ge(y))
+    });
+    let unsigned = ctx.func::<(u32, u32), (bool, bool, bool, bool)>(|(x, y)| {
+        (x.lt(y), x.le(y), x.gt(y), x.ge(y))
+    });
+    let signed = ctx.get_compiled_function(signed);
+    let unsigned = ctx.get_compiled_function(unsigned);
+    assert_eq!(signed.call((-1, 1)), (true, true, false, false));
+    assert_eq!(unsigned.call((u32::MAX, 1)), (false, false, true, true));
+    assert_eq!(
+        signed.call((i32::MIN, i32::MAX)),
+        (true, true, false, false)
+    );
+    assert_eq!(
+        unsigned.call((1 << 31, (1 << 31) - 1)),
+        (false, false, true, true)
+    );
+    assert_eq!(signed.call((7, 7)), (false, true, false, true));
+    assert_eq!(unsigned.call((7, 7)), (false, true, false, true));
+}
+
+#[test]
+fn signed_unsigned_clamp() {
+    let mut ctx = Ctx::builder().build();
+    let signed =
+        ctx.func::<i8, (i8, i8, i8)>(|x| (x.clamp(-5i8, 5i8), x.min(0i8), x.value().max(0i8)));
+    let unsigned =
+        ctx.func::<u8, (u8, u8, u8)>(|x| (x.clamp(5u8, 200u8), x.min(100u8), x.value().max(100u8)));
+    let signed = ctx.get_compiled_function(signed);
+    let unsigned = ctx.get_compiled_function(unsigned);
+    assert_eq!(signed.call(-100), (-5, -100, 0));
+    assert_eq!(signed.call(100), (5, 0, 100));
+    assert_eq!(signed.call(3), (3, 0, 3));
+    // 0xff is 255, not -1
+    assert_eq!(unsigned.call(0xff), (200, 100, 0xff));
+    assert_eq!(unsigned.call(0), (5, 0, 100));
+    assert_eq!(unsigned.call(50), (50, 50, 100));
+}
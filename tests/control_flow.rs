@@ -0,0 +1,324 @@
+use lego::ffi::Function;
+use lego::prelude::*;
+
+#[test]
+fn loop_statement() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<i32, i32>(|n| {
+        lego!({
+            let mut i = Var::new(0i32);
+            loop {
+                if i.value().ge(n) {
+                    break;
+                }
+                i += 1i32;
+            }
+            i.value()
+        })
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(5), 5);
+    assert_eq!(f.call(-1), 0);
+}
+
+#[test]
+fn loop_without_break() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<i32, i32>(|n| {
+        lego!({
+            let mut i = Var::new(0i32);
+            loop {
+                if i.value().ge(n) {
+                    return i.value();
+                }
+                i += 1i32;
+            }
+            i.value()
+        })
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(3), 3);
+}
+
+#[test]
+fn loop_value() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<i32, i32>(|n| {
+        lego!({
+            let mut i = Var::new(1i32);
+            let x: Val<i32> = loop {
+                if (i.value() * i.value()).gt(n) {
+                    break i.value();
+                }
+                i += 1i32;
+            };
+            x
+        })
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(10), 4);
+    assert_eq!(f.call(0), 1);
+}
+
+#[test]
+#[should_panic(expected = "the value passed to `break` doesn't match the type of the loop")]
+fn jiter_break_mismatch() {
+    let mut ctx = Ctx::builder().build();
+    ctx.func::<&[u64], u64>(|s| {
+        s.into_jiter().fold(0u64.value(), |acc, x| {
+            lego!({
+                if x.deref() == 0 {
+                    break Val::new(1i32);
+                }
+                acc + x.deref()
+            })
+        })
+    });
+}
+
+const OP_DOUBLE: u8 = 2;
+const BIG: i128 = 1 << 100;
+
+#[test]
+fn staged_match() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u8, u64>(|op| {
+        lego!({
+            // only marked matches are staged, this one is evaluated at build time
+            let host = match BIG {
+                BIG => 100u64,
+                _ => 200u64,
+            };
+            let r = #[staged]
+            match op {
+                0 => Val::new(1u64),
+                1 | OP_DOUBLE => Val::new(2u64),
+                other => {
+                    if other > 10 {
+                        return Val::new(0u64);
+                    }
+                    Val::new(3u64)
+                }
+            };
+            r + host
+        })
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(0), 101);
+    assert_eq!(f.call(1), 102);
+    assert_eq!(f.call(2), 102);
+    assert_eq!(f.call(5), 103);
+    assert_eq!(f.call(11), 0);
+}
+
+#[test]
+fn range_adaptor_chains() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<i32, (i32, i32, usize)>(|n| {
+        // `step_by` is the adaptor of every iterator, `step` the range specific one
+        let evens = range(Val::new(0i32), n).step_by(2usize).sum();
+        let threes = range_inclusive(0i32, n)
+            .step(3i32)
+            .rev()
+            .step_by(2usize)
+            .sum();
+        let count = range(0i32, n).step(2i32).step_by(3usize).count();
+        (evens, threes, count)
+    });
+    let f = ctx.get_compiled_function(f);
+    // 0 + 2 + 4 + 6 + 8
+    // 9, 3 out of 9, 6, 3, 0
+    // 0, 6 out of 0, 2, 4, 6, 8
+    assert_eq!(f.call(10), (20, 12, 2));
+    assert_eq!(f.call(0), (0, 0, 0));
+    assert_eq!(f.call(-3), (0, 0, 0));
+}
+
+#[test]
+fn jiter_adaptors() {
+    let mut ctx = Ctx::builder().build();
+    let dot = ctx.func::<(&[i32], &[i32]), i32>(|(a, b)| {
+        a.into_jiter()
+            .zip(b)
+            .fold(0i32.value(), |acc, (x, y)| acc + x.get() * y.get())
+    });
+    let weighted = ctx.func::<&[usize], usize>(|s| {
+        s.into_jiter()
+            .enumerate()
+            .fold(0usize.value(), |acc, (i, x)| acc + x.get() * i)
+    });
+    let window = ctx.func::<(&[u32], usize, usize), u32>(|(s, skip, take)| {
+        s.into_jiter()
+            .skip(skip)
+            .take(take)
+            .fold(0u32.value(), |acc, x| acc + x.get())
+    });
+    let stepped = ctx.func::<(&[u32], usize), u32>(|(s, step)| {
+        s.into_jiter()
+            .step_by(step)
+            .fold(0u32.value(), |acc, x| acc * 10u32 + x.get())
+    });
+    let chained = ctx.func::<(&[u32], &[u32]), u32>(|(a, b)| {
+        a.into_jiter()
+            .chain(b.into_jiter().rev())
+            .filter(|x| x.get().neq(0u32))
+            .take(5usize)
+            .fold(0u32.value(), |acc, x| acc * 10u32 + x.get())
+    });
+    let dot = ctx.get_compiled_function(dot);
+    let weighted = ctx.get_compiled_function(weighted);
+    let window = ctx.get_compiled_function(window);
+    let stepped = ctx.get_compiled_function(stepped);
+    let chained = ctx.get_compiled_function(chained);
+    assert_eq!(dot.call((&[1, 2, 3][..], &[4, 5, 6, 7][..])), 32);
+    assert_eq!(dot.call((&[1, 2, 3][..], &[][..])), 0);
+    assert_eq!(weighted.call(&[5, 6, 7][..]), 6 + 14);
+    let d = [1u32, 2, 3, 4, 5, 6];
+    assert_eq!(window.call((&d[..], 2, 3)), 3 + 4 + 5);
+    assert_eq!(window.call((&d[..], 4, 10)), 11);
+    assert_eq!(window.call((&d[..], 10, 1)), 0);
+    assert_eq!(stepped.call((&d[..], 2)), 135);
+    assert_eq!(stepped.call((&d[..], 1)), 123456);
+    assert_eq!(chained.call((&[1, 0, 2][..], &[3, 4, 0, 5][..])), 12543);
+    assert_eq!(chained.call((&[][..], &[3, 4][..])), 43);
+}
+
+#[test]
+fn take_and_zip_stop_early() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<(&[u32], usize), (u32, usize, usize)>(|(s, n)| {
+        let mut seen_take = Var::new(0usize);
+        let taken = s
+            .into_jiter()
+            .filter(|_| {
+                seen_take += 1usize;
+                Val::new(true)
+            })
+            .take(n)
+            .fold(0u32.value(), |acc, x| acc + x.get());
+        let mut seen_zip = Var::new(0usize);
+        range(0usize, n)
+            .zip(s.into_jiter().filter(|_| {
+                seen_zip += 1usize;
+                Val::new(true)
+            }))
+            .count();
+        (taken, seen_take.value(), seen_zip.value())
+    });
+    let f = ctx.get_compiled_function(f);
+    let d = [1u32, 2, 3, 4, 5];
+    // the inner iterators are only advanced for the items that are yielded
+    assert_eq!(f.call((&d[..], 2)), (3, 2, 2));
+    assert_eq!(f.call((&d[..], 0)), (0, 0, 0));
+    assert_eq!(f.call((&d[..], 10)), (15, 5, 5));
+}
+
+#[test]
+fn macro_comparisons() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<(i64, i64), (bool, bool, bool, bool, bool, bool)>(|(x, y)| {
+        // comparisons of host values are folded while building
+        let host: bool = lego!({ 1 < 2 && "a" == "a" && BIG != 0 });
+        assert!(host);
+        let eq: Val<bool> = lego!({ x == y });
+        let ne: Val<bool> = lego!({ x != 3 });
+        let lt: Val<bool> = lego!({ x < y.value() });
+        let le: Val<bool> = lego!({ x.value() <= 3 });
+        let gt: Val<bool> = lego!({ -3 > y });
+        let ge: Val<bool> = lego!({ x >= -1 });
+        (eq, ne, lt, le, gt, ge)
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call((3, 3)), (true, false, false, true, false, true));
+    assert_eq!(f.call((-2, 5)), (false, true, true, true, false, false));
+    assert_eq!(f.call((4, -4)), (false, true, false, false, true, true));
+}
+
+#[test]
+fn select_tuples() {
+    let mut ctx = Ctx::builder().disasm(true).build();
+    let f = ctx.func::<(u64, u64), (u64, u64, f64)>(|(x, y)| {
+        let c = x.value().lt(y);
+        let (lo, hi) = select(c, (x.value(), y.value()), (y.value(), x.value()));
+        let scale = c.select(Val::new(1.5f64), Val::new(2.5f64));
+        // a host condition picks the value while building
+        let offset = select(true, Val::new(100u64), Val::new(0u64));
+        (lo, hi + offset, scale)
+    });
+    let g = ctx.func::<(&[u64], u64), u64>(|(s, i)| {
+        let r = select(i.value().eq(0u64), s.get(0usize), s.get(1usize));
+        r.deref()
+    });
+    // the values are selected without branching
+    let ir = ctx.inspect(f).unwrap().ir().unwrap();
+    assert!(ir.contains("select"));
+    assert!(!ir.contains("brif"));
+    let f = ctx.get_compiled_function(f);
+    let g = ctx.get_compiled_function(g);
+    assert_eq!(f.call((1, 2)), (1, 102, 1.5));
+    assert_eq!(f.call((5, 2)), (2, 105, 2.5));
+    assert_eq!(g.call((&[3, 4], 0)), 3);
+    assert_eq!(g.call((&[3, 4], 1)), 4);
+}
+
+#[test]
+fn jiter_reductions() {
+    let mut ctx = Ctx::builder().build();
+    let stats = ctx.func::<&[i32], (i32, i32, usize)>(|s| {
+        (
+            s.into_jiter().sum(),
+            s.into_jiter().product(),
+            s.into_jiter().count(),
+        )
+    });
+    let fstats = ctx.func::<&[f64], (f64, f64)>(|s| {
+        (
+            s.into_jiter().map(|x| x.get()).sum(),
+            s.into_jiter().product(),
+        )
+    });
+    let bounds = ctx.func::<&[i64], (bool, i64, i64)>(|s| {
+        let min = s.into_jiter().min();
+        let max = s.into_jiter().max();
+        let zero = Val::new(0i64);
+        (min.is_some(), min.unwrap_or(zero), max.unwrap_or(zero))
+    });
+    // indexes of the items closest to and farthest from 10
+    let by_key = ctx.func::<&[i32], (bool, usize, usize)>(|s| {
+        let items = || s.into_jiter().map(|x| x.get()).enumerate();
+        let dist = |(_, x): &(Val<usize>, Val<i32>)| {
+            let d = *x - 10i32;
+            d.max(Val::new(0i32) - d)
+        };
+        let closest = items().min_by_key(dist);
+        let farthest = items().max_by_key(dist);
+        let none = (Val::new(usize::MAX), Val::new(0i32));
+        (
+            closest.is_some(),
+            closest.unwrap_or(none).0,
+            farthest.unwrap_or(none).0,
+        )
+    });
+    let position = ctx.func::<(&[i32], i32), usize>(|(s, x)| {
+        s.into_jiter()
+            .position(|it| it.get().eq(x))
+            .unwrap_or(Val::new(usize::MAX))
+    });
+    let stats = ctx.get_compiled_function(stats);
+    let fstats = ctx.get_compiled_function(fstats);
+    let bounds = ctx.get_compiled_function(bounds);
+    let by_key = ctx.get_compiled_function(by_key);
+    let position = ctx.get_compiled_function(position);
+    assert_eq!(stats.call(&[1, -2, 3, 4][..]), (6, -24, 4));
+    assert_eq!(stats.call(&[][..]), (0, 1, 0));
+    assert_eq!(fstats.call(&[1.5, 2.0][..]), (3.5, 3.0));
+    assert_eq!(fstats.call(&[][..]), (0.0, 1.0));
+    assert_eq!(bounds.call(&[3, -7, 12, 0][..]), (true, -7, 12));
+    assert_eq!(bounds.call(&[][..]), (false, 0, 0));
+    assert_eq!(by_key.call(&[0, 9, 11, 30, -10][..]), (true, 1, 4));
+    // like `Iterator::min_by_key` the first of equal items is kept, and the last by `max_by_key`
+    assert_eq!(by_key.call(&[9, 11, 0, 20][..]), (true, 0, 3));
+    assert_eq!(by_key.call(&[][..]), (false, usize::MAX, usize::MAX));
+    assert_eq!(position.call((&[4, 2, 2][..], 2)), 1);
+    assert_eq!(position.call((&[4, 2, 2][..], 3)), usize::MAX);
+}
@@ -0,0 +1,230 @@
+use lego::ffi::Function;
+use lego::prelude::*;
+
+#[test]
+fn recursion() {
+    let mut ctx = Ctx::builder().build();
+    let fib = ctx.declare_func::<u64, u64>("fib");
+    ctx.define_func(fib, |n| {
+        lego!({
+            if n < 2 {
+                return n.value();
+            }
+            fib.call(n.value() - 1u64) + fib.call(n.value() - 2u64)
+        })
+    });
+    let f = ctx.get_compiled_function(fib);
+    assert_eq!(f.call(10), 55);
+}
+
+#[test]
+fn mutual_recursion() {
+    let mut ctx = Ctx::builder().build();
+    let even = ctx.declare_func::<u64, u64>("even");
+    let odd = ctx.declare_func::<u64, u64>("odd");
+    ctx.define_func(even, |n| {
+        lego!({
+            if n == 0 {
+                return 1u64.value();
+            }
+            odd.call(n.value() - 1u64)
+        })
+    });
+    // nothing can be compiled while `odd` isn't defined
+    let err = ctx.try_get_compiled_function(even).err().unwrap();
+    assert!(matches!(&err, LegoError::UndefinedFuncs(names) if names == &["odd"]));
+    ctx.define_func(odd, |n| {
+        lego!({
+            if n == 0 {
+                return 0u64.value();
+            }
+            even.call(n.value() - 1u64)
+        })
+    });
+    let anon = ctx.func::<u64, u64>(|n| even.call(n.value()) + 10u64);
+    let f = ctx.get_compiled_function(anon);
+    assert_eq!(f.call(7), 10);
+    assert_eq!(f.call(8), 11);
+}
+
+#[test]
+fn redeclare_defined_func() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.declare_func::<u64, u64>("f");
+    ctx.define_func(f, |x| x + 1u64);
+    // declaring the name again returns the same, defined, function
+    let again = ctx.declare_func::<u64, u64>("f");
+    let g = ctx.func::<u64, u64>(|x| again.call(x) * 2u64);
+    assert_eq!(ctx.get_compiled_function(g).call(1), 4);
+    assert_eq!(ctx.get_compiled_function(again).call(1), 2);
+
+    // with another signature, the declaration fails
+    assert!(ctx.try_declare_func::<(u64, u64), u64>("f").is_err());
+}
+
+#[test]
+#[should_panic(expected = "functions are declared but not defined: g")]
+fn undefined_func() {
+    let mut ctx = Ctx::builder().build();
+    let _g = ctx.declare_func::<u64, u64>("g");
+    let f = ctx.func::<u64, u64>(|n| n.value());
+    ctx.get_compiled_function(f);
+}
+
+#[test]
+fn get_func() {
+    let mut ctx = Ctx::builder().build();
+    let double = ctx.declare_func::<u64, u64>("double");
+    ctx.define_func(double, |x| x * 2u64);
+    let found = ctx.get_func::<u64, u64>("double").unwrap();
+    assert_eq!(ctx.get_compiled_function(found).call(21), 42);
+    // the signature must match
+    assert!(ctx.get_func::<(u64, u64), u64>("double").is_none());
+    assert!(ctx.get_func::<u64, u32>("double").is_none());
+    assert!(ctx.get_func::<u64, u64>("nope").is_none());
+    // declared functions can be looked up before they are defined
+    ctx.declare_func::<u32, u32>("later");
+    assert!(ctx.get_func::<u32, u32>("later").is_some());
+}
+
+#[test]
+fn reset() {
+    let mut ctx = Ctx::builder().build();
+    for i in 0..2000u64 {
+        // the environments of the host closures are freed by the reset too
+        let f = ctx.func::<u64, u64>(|x| {
+            let add = (move |x: u64| x + i).into_host_fn();
+            add.call(x) * 2u64
+        });
+        let f = ctx.get_compiled_function(f);
+        assert_eq!(f.call(1), (i + 1) * 2);
+        ctx.reset();
+    }
+}
+
+#[test]
+#[should_panic(expected = "function belongs to another context, or was freed by a reset")]
+fn compile_after_reset() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, u64>(|x| x.value() + 1u64);
+    ctx.reset();
+    ctx.get_compiled_function(f);
+}
+
+#[test]
+#[should_panic(expected = "function belongs to another context, or was freed by a reset")]
+fn call_after_reset() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, u64>(|x| x.value() + 1u64);
+    ctx.reset();
+    ctx.func::<u64, u64>(|x| f.call(x));
+}
+
+#[test]
+fn verifier_error() {
+    let mut ctx = Ctx::builder().build();
+    let err = ctx
+        .try_func::<u64, u64>(|x| {
+            let mut escaped = None;
+            lego!({
+                if x > 1 {
+                    escaped = Some(x.value() * 2u64);
+                }
+            });
+            // the value is only defined in the branch, it doesn't dominate its use
+            escaped.unwrap()
+        })
+        .err()
+        .unwrap();
+    match &err {
+        LegoError::Verifier { errors, ir } => {
+            assert!(!errors.0.is_empty());
+            assert_eq!(err.ir(), Some(ir.as_str()));
+            assert!(err.to_string().starts_with("verifier error: "));
+        }
+        other => panic!("unexpected error: {other}"),
+    }
+
+    // the failed function doesn't leave the context in a broken state
+    let f = ctx.func::<u64, u64>(|x| x.value() + 1u64);
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(41), 42);
+}
+
+#[test]
+fn builder_settings() {
+    for opt_level in [OptLevel::None, OptLevel::Speed, OptLevel::SpeedAndSize] {
+        let mut ctx = Ctx::builder()
+            .opt_level(opt_level)
+            .verifier(false)
+            .probestack(true)
+            .flag("regalloc_checker", "true")
+            .build();
+        let f = ctx.func::<&[u64], u64>(|s| s.into_jiter().sum());
+        let f = ctx.get_compiled_function(f);
+        assert_eq!(f.call(&[1, 2, 3]), 6);
+    }
+}
+
+#[test]
+fn builder_invalid_settings() {
+    let e = Ctx::builder()
+        .flag("no_such_flag", "1")
+        .try_build()
+        .err()
+        .unwrap();
+    assert!(matches!(
+        e,
+        LegoError::InvalidSetting { ref name, ref value, .. } if name == "no_such_flag" && value == "1"
+    ));
+    assert!(e
+        .to_string()
+        .starts_with("invalid setting `no_such_flag=1`"));
+    // the value of a known flag is checked too
+    let e = Ctx::builder()
+        .flag("opt_level", "fastest")
+        .try_build()
+        .err()
+        .unwrap();
+    assert!(matches!(e, LegoError::InvalidSetting { ref name, .. } if name == "opt_level"));
+    let e = Ctx::builder()
+        .isa_flag("has_nothing", "true")
+        .try_build()
+        .err()
+        .unwrap();
+    assert!(matches!(e, LegoError::InvalidSetting { ref name, .. } if name == "has_nothing"));
+    let e = Ctx::builder()
+        .flag("no_such_flag", "1")
+        .try_build_object("obj")
+        .err()
+        .unwrap();
+    assert!(matches!(e, LegoError::InvalidSetting { .. }));
+}
+
+#[test]
+#[should_panic(expected = "invalid setting `no_such_flag=1`")]
+fn builder_invalid_setting_panics() {
+    Ctx::builder().flag("no_such_flag", "1").build();
+}
+
+#[test]
+fn inspect() {
+    let mut ctx = Ctx::builder().disasm(true).build();
+    let f = ctx.func::<u64, u64>(|x| x.value() * 3u64);
+    let info = ctx.inspect(f).unwrap();
+    assert!(info.ir().unwrap().contains("imul"));
+    assert!(!info.disasm().unwrap().is_empty());
+    assert!(info.code_size() > 0);
+    let later = ctx.declare_func::<u64, u64>("later");
+    assert!(ctx.inspect(later).is_none());
+    ctx.define_func(later, |x| f.call(x));
+    assert!(ctx.inspect(later).unwrap().ir().unwrap().contains("call"));
+
+    // only the code size is kept by default
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, u64>(|x| x.value() * 3u64);
+    let info = ctx.inspect(f).unwrap();
+    assert!(info.ir().is_none());
+    assert!(info.disasm().is_none());
+    assert!(info.code_size() > 0);
+}
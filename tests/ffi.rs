@@ -0,0 +1,278 @@
+use lego::ffi::Function;
+use lego::prelude::*;
+
+fn check(x: u64) -> u64 {
+    if x == 0 {
+        panic!("zero");
+    }
+    x
+}
+
+#[test]
+fn host_panic_returning_ref() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<&u64, &u64>(|x| {
+        let checked = (|x: u64| check(x)).into_host_fn();
+        checked.call(x.deref());
+        x.value()
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(*f.call(&3), 3);
+    let payload = f.try_call(&0).unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"zero"));
+}
+
+#[test]
+fn host_panic_returning_tuple() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, (bool, *const u64)>(|x| {
+        let checked = (|x: u64| check(x)).into_host_fn();
+        let x = checked.call(x);
+        let leaked = (|x: u64| -> *const u64 { Box::leak(Box::new(x)) }).into_host_fn();
+        (x.eq(Val::new(1u64)), leaked.call(x))
+    });
+    let f = ctx.get_compiled_function(f);
+    let (one, ptr) = f.call(1);
+    assert!(one);
+    assert_eq!(unsafe { *ptr }, 1);
+    assert!(f.try_call(0).is_err());
+}
+
+#[test]
+fn host_closure_env() {
+    use std::rc::Rc;
+
+    let mut ctx = Ctx::builder().build();
+    let name = String::from("lego");
+    let owner = Rc::new(());
+    let witness = owner.clone();
+    let f = ctx.func::<(u64, u64, u64), (u64, i64)>(move |(a, b, c)| {
+        let name = name.clone();
+        let owner = owner.clone();
+        // the closure captures non-Copy state, and is called twice
+        let mul_add = (move |a: u64, b: u64, c: u64| {
+            let _ = &owner;
+            a * b + c + name.len() as u64
+        })
+        .into_host_fn();
+        let seven = (|a: u8, b: u16, c: u32, d: u64, e: i8, f: i16, g: i32| -> i64 {
+            a as i64 + b as i64 + c as i64 + d as i64 + e as i64 + f as i64 + g as i64
+        })
+        .into_host_fn();
+        let x = mul_add.call((a, b, c));
+        let y = seven.call((1u8, 2u16, 3u32, 4u64, -5i8, 6i16, -7i32));
+        (mul_add.call((x, 2u64, c)), y)
+    });
+    let f = ctx.get_compiled_function(f);
+    // (2 * 3 + 1 + 4) * 2 + 1 + 4
+    assert_eq!(f.call((2, 3, 1)), (27, 4));
+    assert_eq!(Rc::strong_count(&witness), 2);
+    // the environments are dropped with the code
+    drop(ctx);
+    assert_eq!(Rc::strong_count(&witness), 1);
+}
+
+#[test]
+fn proxy_vec() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, (usize, u64)>(|x| {
+        let mut v = Proxy::<Vec<u64>>::new();
+        v.push(x.value());
+        v.push(x + 1u64);
+        let sum = v.as_slice().into_jiter().map(|x| x.get()).sum();
+        (v.len(), sum)
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call(20), (2, 41));
+}
+
+extern "C" fn host_add(a: u64, b: u64) -> u64 {
+    a + b
+}
+
+extern "C" fn host_neg(a: i32) -> i32 {
+    -a
+}
+
+#[test]
+fn named_host_fns() {
+    let mut ctx = Ctx::builder()
+        .disasm(true)
+        .register_host_functions(lego::host_fns!(
+            host_add => extern "C" fn(u64, u64) -> u64,
+            host_neg => extern "C" fn(i32) -> i32,
+        ))
+        .build();
+    let add = ctx.import_func::<(u64, u64), u64>("host_add");
+    let neg = ctx.import_func::<i32, i32>("host_neg");
+    let e = ctx.try_import_func::<u64, u64>("host_add").err().unwrap();
+    assert!(matches!(e, LegoError::HostFnSignature { ref name, .. } if name == "host_add"));
+    // the call conv depends on the platform
+    let msg = e.to_string();
+    assert!(msg.starts_with("host function `host_add` has signature `(i64, i64) -> i64"));
+    assert!(msg.contains("but was imported as `(i64) -> i64"));
+    let e = ctx.try_import_func::<u64, u64>("host_sub").err().unwrap();
+    assert!(matches!(e, LegoError::UnknownHostFn(ref name) if name == "host_sub"));
+
+    let f = ctx.func::<(u64, i32), (u64, i32)>(|(a, b)| (add.call((a, 2u64)), neg.call(b)));
+    // the host functions are called by name
+    let info = ctx.inspect(f).unwrap();
+    assert!(info.ir().unwrap().contains("= host_add"));
+    assert!(info.ir().unwrap().contains("= host_neg"));
+    assert!(info.disasm().unwrap().contains("host_add"));
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call((40, 1)), (42, -1));
+}
+
+#[test]
+fn mixed_params() {
+    let mut ctx = Ctx::builder().build();
+    type Prims = (u8, i16, u32, i64, f32, f64, usize);
+    let f = ctx.func::<Prims, Prims>(|(a, b, c, d, e, f, g)| {
+        (
+            a + 1u8,
+            b * 2i16,
+            c - 1u32,
+            d * -1i64,
+            e * 2.0f32,
+            f + 0.5f64,
+            g + 1usize,
+        )
+    });
+    let g = ctx.func::<(i64, u32, i8, &[u64], i64, f32, &mut u64), i64>(
+        |(a, _b, _c, s, e, _f, mut out)| {
+            out.put(s.into_jiter().sum());
+            (a + e).value()
+        },
+    );
+    let f = ctx.get_compiled_function(f);
+    let g = ctx.get_compiled_function(g);
+    assert_eq!(
+        f.call((1, -3, 7, 40, 1.25, -1.0, usize::MAX - 1)),
+        (2, -6, 6, -40, 2.5, -0.5, usize::MAX)
+    );
+    let mut out = 0;
+    assert_eq!(g.call((40, 1, -1, &[1, 2, 3], 2, 0.5, &mut out)), 42);
+    assert_eq!(out, 6);
+}
+
+#[test]
+fn mutable_slices() {
+    let mut ctx = Ctx::builder().build();
+    let get_unchecked = ctx.func::<&[u32], u32>(|s| unsafe { s.get_unchecked(1usize) }.get());
+    let mutate = ctx.func::<(&mut [u32], usize), ()>(|(mut s, i)| {
+        s.set(i, 7u32);
+        s.swap(0usize, 2usize);
+        let x = s.get(1usize).get();
+        s.get_mut(3usize).put(x + 1u32);
+    });
+    let fill = ctx.func::<(&mut [u64], u64), ()>(|(mut s, v)| s.fill(v));
+    let double = ctx.func::<&mut [i32], ()>(|s| {
+        s.into_jiter().for_each(|mut r| {
+            let v = r.get();
+            r.put(v * 2i32);
+        })
+    });
+    let get_unchecked = ctx.get_compiled_function(get_unchecked);
+    let mutate = ctx.get_compiled_function(mutate);
+    let fill = ctx.get_compiled_function(fill);
+    let double = ctx.get_compiled_function(double);
+    assert_eq!(get_unchecked.call(&[1, 2, 3][..]), 2);
+    let mut data = [1, 2, 3, 4];
+    mutate.call((&mut data[..], 1));
+    assert_eq!(data, [3, 7, 1, 8]);
+    let mut data = [0; 5];
+    fill.call((&mut [][..], 1));
+    fill.call((&mut data[..], 9));
+    assert_eq!(data, [9; 5]);
+    let mut data = [1, -2, 3];
+    double.call(&mut data[..]);
+    assert_eq!(data, [2, -4, 6]);
+}
+
+fn sum(s: Slice<u32>) -> Val<u32> {
+    s.into_jiter().sum()
+}
+
+#[test]
+fn slice_views() {
+    let mut ctx = Ctx::builder().build();
+    let mid = ctx.func::<(&[u32], usize, usize), u32>(|(s, a, b)| sum(s.slice(a..b)));
+    let ranges = ctx.func::<&[u32], (u32, u32, u32, u32)>(|s| {
+        let two = Val::new(2usize);
+        (
+            sum(s.slice(two..)),
+            sum(s.slice(..two)),
+            sum(s.slice(1usize..=2usize)),
+            sum(s.slice(..)),
+        )
+    });
+    let split = ctx.func::<(&[u32], usize), (u32, u32)>(|(s, m)| {
+        let (l, r) = s.split_at(m);
+        (sum(l), sum(r))
+    });
+    let ends = ctx.func::<&[u32], (u32, u32)>(|s| (s.first().get(), s.last().get()));
+    let mid = ctx.get_compiled_function(mid);
+    let ranges = ctx.get_compiled_function(ranges);
+    let split = ctx.get_compiled_function(split);
+    let ends = ctx.get_compiled_function(ends);
+    let data = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+    let d = &data[..];
+    assert_eq!(mid.call((d, 2, 5)), 3 + 4 + 5);
+    assert_eq!(mid.call((d, 4, 4)), 0);
+    assert_eq!(ranges.call(&data[..4]), (7, 3, 5, 10));
+    assert_eq!(split.call((d, 3)), (6, 39));
+    assert_eq!(split.call((d, 0)), (0, 45));
+    assert_eq!(split.call((d, 9)), (45, 0));
+    assert_eq!(ends.call(d), (1, 9));
+}
+
+#[test]
+fn slice_chunks() {
+    let mut ctx = Ctx::builder().build();
+    // the largest chunk, and the sum of the squared lengths of the chunks
+    let chunks = ctx.func::<(&[u32], usize), (u32, usize)>(|(s, n)| {
+        s.chunks(n)
+            .fold((0u32.value(), 0usize.value()), |(max, lens), c| {
+                (max.max(sum(c)), lens + c.len() * c.len())
+            })
+    });
+    let dot4 = ctx.func::<&[u32], u32>(|s| {
+        s.chunks_exact(4usize).fold(0u32.value(), |acc, c| {
+            let mut acc = acc;
+            for i in 0..4usize {
+                acc = acc + c.get(i).get() * (i as u32 + 1);
+            }
+            acc
+        })
+    });
+    // the chunk size is known while building, the loop over a chunk is unrolled
+    let dot3 = ctx.func::<&[u32], u32>(|s| {
+        s.array_chunks::<3>().fold(0u32.value(), |acc, c| {
+            let mut acc = acc;
+            for i in 0..c.len() {
+                acc = acc + c.get(i).get() * (i as u32 + 1);
+            }
+            acc
+        })
+    });
+    let windows = ctx.func::<(&[u32], usize), u32>(|(s, n)| {
+        s.windows(n).fold(0u32.value(), |acc, w| acc.max(sum(w)))
+    });
+    let chunks = ctx.get_compiled_function(chunks);
+    let dot4 = ctx.get_compiled_function(dot4);
+    let dot3 = ctx.get_compiled_function(dot3);
+    let windows = ctx.get_compiled_function(windows);
+    let data = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+    let d = &data[..];
+    assert_eq!(chunks.call((d, 4)), (26, 16 + 16 + 1));
+    assert_eq!(chunks.call((d, 20)), (45, 81));
+    assert_eq!(chunks.call((&[], 3)), (0, 0));
+    // the remainder is left out
+    assert_eq!(dot4.call(d), (1 + 4 + 9 + 16) + (5 + 12 + 21 + 32));
+    assert_eq!(dot3.call(&d[..7]), (1 + 4 + 9) + (4 + 10 + 18));
+    assert_eq!(dot3.call(&d[..2]), 0);
+    assert_eq!(windows.call((d, 3)), 7 + 8 + 9);
+    assert_eq!(windows.call((d, 9)), 45);
+    assert_eq!(windows.call((d, 10)), 0);
+}
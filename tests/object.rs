@@ -0,0 +1,26 @@
+use lego::prelude::*;
+
+#[test]
+fn no_panic_check_in_object() {
+    let mut ctx = Ctx::builder().disasm(true).build_object("test");
+    let inc = ctx.func::<u64, u64>(|x| x + 1u64);
+    let f = ctx.func::<u64, u64>(|x| inc.call(x));
+    let ir = ctx.inspect(f).unwrap().ir().unwrap();
+    // the panic slot is a host address, it has no meaning in the object
+    assert!(!ir.contains("load.i8"), "{ir}");
+    assert!(!ctx.emit().unwrap().is_empty());
+}
+
+#[test]
+fn host_closure_in_object() {
+    let mut ctx = Ctx::builder().build_object("test");
+    let res = ctx.try_func::<u64, u64>(|x| {
+        let double = (|x: u64| x * 2).into_host_fn();
+        double.call(x)
+    });
+    assert!(matches!(res, Err(LegoError::HostClosureInObject)));
+
+    // the context can still be used
+    ctx.func::<u64, u64>(|x| x + 1u64);
+    assert!(!ctx.emit().unwrap().is_empty());
+}
@@ -0,0 +1,203 @@
+#![cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+
+use lego::ffi::Function;
+use lego::prelude::*;
+
+#[test]
+fn division_by_zero() {
+    let mut ctx = Ctx::builder().build();
+    let div = ctx.func::<(u64, u64), u64>(|(a, b)| a / b);
+    let outer = ctx.func::<(u64, u64), u64>(|(a, b)| div.call((a, b)) + 1u64);
+    let div = ctx.get_compiled_function(div);
+    let outer = ctx.get_compiled_function(outer);
+    assert_eq!(div.call_checked((10, 2)), Ok(5));
+    assert_eq!(div.call_checked((10, 0)), Err(Trap::DivisionByZero));
+    assert_eq!(outer.call_checked((1, 0)), Err(Trap::DivisionByZero));
+    // the thread keeps running normally after a trap
+    assert_eq!(outer.call_checked((9, 3)), Ok(4));
+    assert_eq!(div.call((9, 3)), 3);
+}
+
+#[test]
+fn user_trap() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, u64>(|x| {
+        lego!({
+            if x == 0 {
+                trap(42);
+            }
+            x + 1u64
+        })
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call_checked(1), Ok(2));
+    assert_eq!(f.call_checked(0), Err(Trap::User(42)));
+    assert_eq!(f.call_checked(2), Ok(3));
+}
+
+#[test]
+fn host_panic_in_checked_call() {
+    let mut ctx = Ctx::builder().build();
+    let f = ctx.func::<u64, u64>(|x| {
+        let check = (|x: u64| {
+            assert_ne!(x, 0);
+            x
+        })
+        .into_host_fn();
+        check.call(x)
+    });
+    let f = ctx.get_compiled_function(f);
+    assert_eq!(f.call_checked(1), Ok(1));
+    let res = std::panic::catch_unwind(|| f.call_checked(0));
+    assert!(res.is_err());
+}
+
+#[test]
+fn traps_in_threads() {
+    let threads = (0..8)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let mut ctx = Ctx::builder().build();
+                let f = ctx.func::<(u64, u64), u64>(|(a, b)| a / b);
+                let f = ctx.get_compiled_function(f);
+                (0..100)
+                    .map(|j| f.call_checked((j, (i + j) % 3)))
+                    .filter(|r| r.is_err())
+                    .count()
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        assert!(t.join().unwrap() > 0);
+    }
+}
+
+#[test]
+fn user_trap_codes() {
+    let mut ctx = Ctx::builder().build();
+    let low = ctx.func::<u8, ()>(|_| trap(1));
+    let high = ctx.func::<u8, ()>(|_| trap(250));
+    let low = ctx.get_compiled_function(low);
+    let high = ctx.get_compiled_function(high);
+    assert_eq!(low.call_checked(0), Err(Trap::User(1)));
+    assert_eq!(high.call_checked(0), Err(Trap::User(250)));
+}
+
+#[test]
+fn checked_call_arguments() {
+    let mut ctx = Ctx::builder().build();
+    type Mixed = (i8, f32, u64, f64, u8, i32, f32);
+    // 14 integer arguments, some passed on the stack
+    type Slices<'a> = (
+        &'a [usize],
+        &'a [usize],
+        &'a [usize],
+        &'a [usize],
+        &'a [usize],
+        &'a [usize],
+        &'a [usize],
+    );
+    let mixed = ctx.func::<Mixed, Mixed>(|(a, b, c, d, e, f, g)| {
+        (
+            a - 1i8,
+            b * 2.0f32,
+            c + 1u64,
+            d * 2.0f64,
+            e + 1u8,
+            f - 1i32,
+            g * 2.0f32,
+        )
+    });
+    let half = ctx.func::<f32, f32>(|x| x / 2.0f32);
+    let neg = ctx.func::<i8, i8>(|x| Val::new(0i8) - x);
+    let slices = ctx.func::<Slices, usize>(|(a, b, c, d, e, f, g)| {
+        a.len() + b.len() + c.len() + d.len() + e.len() + f.len() + g.get(0usize).deref()
+    });
+    let mixed = ctx.get_compiled_function(mixed);
+    let half = ctx.get_compiled_function(half);
+    let neg = ctx.get_compiled_function(neg);
+    let slices = ctx.get_compiled_function(slices);
+
+    let args = (-3, 0.5, 7, 2.25, 9, -100, 1.5);
+    assert_eq!(mixed.call_checked(args), Ok(mixed.call(args)));
+    assert_eq!(
+        mixed.call_checked(args),
+        Ok((-4, 1.0, 8, 4.5, 10, -101, 3.0))
+    );
+    assert_eq!(half.call_checked(3.0), Ok(1.5));
+    assert_eq!(neg.call_checked(-128), Ok(-128));
+    assert_eq!(neg.call_checked(5), Ok(-5));
+
+    let s = [1usize, 2, 3];
+    let last = [40usize];
+    let args = (
+        &s[..1],
+        &s[..2],
+        &s[..3],
+        &s[..0],
+        &s[..1],
+        &s[..2],
+        &last[..],
+    );
+    assert_eq!(slices.call_checked(args), Ok(1 + 2 + 3 + 1 + 2 + 40));
+    let args = (
+        &s[..1],
+        &s[..2],
+        &s[..3],
+        &s[..0],
+        &s[..1],
+        &s[..2],
+        &s[..0],
+    );
+    assert_eq!(slices.call_checked(args), Err(Trap::OutOfBounds));
+}
+
+#[test]
+fn slice_bounds_checks() {
+    let mut ctx = Ctx::builder().build();
+    let get = ctx.func::<(&[u32], usize), u32>(|(s, i)| s.get(i).get());
+    let set = ctx.func::<(&mut [u32], usize), ()>(|(mut s, i)| s.set(i, 7u32));
+    let swap = ctx.func::<(&mut [u32], usize), ()>(|(mut s, i)| s.swap(0usize, i));
+    let get = ctx.get_compiled_function(get);
+    let set = ctx.get_compiled_function(set);
+    let swap = ctx.get_compiled_function(swap);
+    let data = [1, 2, 3, 4];
+    assert_eq!(get.call_checked((&data[..], 3)), Ok(4));
+    assert_eq!(get.call_checked((&data[..], 4)), Err(Trap::OutOfBounds));
+    assert_eq!(get.call_checked((&[][..], 0)), Err(Trap::OutOfBounds));
+    let mut data = [1, 2, 3, 4];
+    assert_eq!(set.call_checked((&mut data[..], 4)), Err(Trap::OutOfBounds));
+    // nothing was written before the trap
+    assert_eq!(data, [1, 2, 3, 4]);
+    let mut data = [1, 2, 3, 4];
+    assert_eq!(
+        swap.call_checked((&mut data[..], 4)),
+        Err(Trap::OutOfBounds)
+    );
+    assert_eq!(data, [1, 2, 3, 4]);
+}
+
+#[test]
+fn slice_view_bounds_checks() {
+    let mut ctx = Ctx::builder().build();
+    let mid = ctx.func::<(&[u32], usize, usize), usize>(|(s, a, b)| s.slice(a..b).len());
+    let split = ctx.func::<(&[u32], usize), usize>(|(s, m)| s.split_at(m).1.len());
+    let first = ctx.func::<&[u32], u32>(|s| s.first().get());
+    let chunks = ctx.func::<(&[u32], usize), usize>(|(s, n)| s.chunks(n).count());
+    let mid = ctx.get_compiled_function(mid);
+    let split = ctx.get_compiled_function(split);
+    let first = ctx.get_compiled_function(first);
+    let chunks = ctx.get_compiled_function(chunks);
+    let d = &[1, 2, 3][..];
+    assert_eq!(mid.call_checked((d, 1, 3)), Ok(2));
+    assert_eq!(mid.call_checked((d, 2, 1)), Err(Trap::OutOfBounds));
+    assert_eq!(mid.call_checked((d, 1, 4)), Err(Trap::OutOfBounds));
+    assert_eq!(split.call_checked((d, 4)), Err(Trap::OutOfBounds));
+    assert_eq!(first.call_checked(&[]), Err(Trap::OutOfBounds));
+    assert_eq!(chunks.call_checked((d, 2)), Ok(2));
+    // like `<[T]>::chunks`, the chunk size can't be zero
+    assert_eq!(chunks.call_checked((d, 0)), Err(Trap::OutOfBounds));
+}